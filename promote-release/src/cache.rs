@@ -0,0 +1,246 @@
+//! A cache of artifacts downloaded from the CI bucket, kept across runs so
+//! retrying a release doesn't download everything all over again.
+//!
+//! Entries are named after the S3 ETag of the object they were downloaded
+//! from, which changes whenever the object does. ETags aren't always a hash
+//! of the contents though (that depends on how the object was uploaded and
+//! encrypted), so each entry is checked against the SHA-256 recorded next to
+//! it when it was added instead, and thrown away if that doesn't match. That
+//! hash is only recorded once the download has been checked against what the
+//! source says the contents are.
+
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use hashes;
+
+pub struct Cache {
+    dir: PathBuf,
+    max_size: u64,
+}
+
+impl Cache {
+    /// Opens the cache in `dir`, which is kept under `max_size` bytes by
+    /// `evict`.
+    pub fn new(dir: &Path, max_size: u64) -> io::Result<Cache> {
+        fs::create_dir_all(dir)?;
+        Ok(Cache {
+            dir: dir.to_path_buf(),
+            max_size,
+        })
+    }
+
+    fn entry(&self, etag: &str) -> PathBuf {
+        self.dir.join(etag)
+    }
+
+    fn hash_file(entry: &Path) -> PathBuf {
+        entry.with_extension("sha256")
+    }
+
+    /// Puts the cached object with `etag` at `dst`, returning whether there
+    /// was one. Entries that no longer match their recorded hash are thrown
+    /// away, to be downloaded afresh.
+    pub fn get(&self, etag: &str, dst: &Path) -> io::Result<bool> {
+        let entry = self.entry(etag);
+        if !entry.is_file() {
+            return Ok(false)
+        }
+        let expected = fs::read_to_string(Cache::hash_file(&entry)).ok();
+        if expected.as_deref() != Some(&hashes::sha256_file(&entry)?[..]) {
+            println!("cached {} is corrupt, discarding it", entry.display());
+            remove(&entry)?;
+            return Ok(false)
+        }
+        // Eviction goes by modification time, so reusing an entry makes it
+        // the last one to go.
+        File::options().write(true).open(&entry)?.set_modified(SystemTime::now())?;
+        link_or_copy(&entry, dst)?;
+        Ok(true)
+    }
+
+    /// Moves the freshly downloaded `src` into the cache as the object with
+    /// `etag`, leaving a link to it at `src`.
+    ///
+    /// Fails if it isn't `size` bytes, which is how big the object was listed
+    /// as, or if its contents aren't what the source says: they have to hash
+    /// to `sha256` if the source has a `.sha256` next to the object, or else
+    /// to the ETag if that's a plain MD5. Multipart ETags can't be checked
+    /// without knowing the part size, so objects with one and no `.sha256`
+    /// are only checked by size.
+    pub fn insert(&self, etag: &str, src: &Path, size: u64, sha256: Option<&str>)
+        -> io::Result<()>
+    {
+        let actual = fs::metadata(src)?.len();
+        if actual != size {
+            return Err(io::Error::other(format!(
+                "{} is {} bytes, expected {}", src.display(), actual, size)))
+        }
+        let hash = hashes::sha256_file(src)?;
+        let (actual, expected) = match sha256 {
+            Some(sha256) => (hash.clone(), sha256.to_string()),
+            None if is_md5(etag) => (hashes::md5_file(src)?, etag.to_string()),
+            None => (String::new(), String::new()),
+        };
+        if actual != expected {
+            return Err(io::Error::other(format!(
+                "{} has hash {}, expected {}", src.display(), actual, expected)))
+        }
+        let entry = self.entry(etag);
+        fs::write(Cache::hash_file(&entry), hash)?;
+        fs::rename(src, &entry)?;
+        link_or_copy(&entry, src)
+    }
+
+    /// Removes the least recently used entries until the cache is back under
+    /// its maximum size, returning how many bytes were freed.
+    pub fn evict(&self) -> io::Result<u64> {
+        let mut entries = Vec::new();
+        let mut total = 0;
+        for entry in self.dir.read_dir()? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            let path = entry.path();
+            if !metadata.is_file() || path.extension().is_some_and(|e| e == "sha256") {
+                continue
+            }
+            total += metadata.len();
+            entries.push((metadata.modified()?, metadata.len(), path));
+        }
+        entries.sort();
+
+        let mut freed = 0;
+        for (_, len, path) in entries {
+            if total - freed <= self.max_size {
+                break
+            }
+            println!("evicting {} from the artifact cache", path.display());
+            remove(&path)?;
+            freed += len;
+        }
+        Ok(freed)
+    }
+}
+
+/// Whether `etag` is a plain MD5 rather than that of a multipart upload,
+/// which ends in `-<parts>`.
+fn is_md5(etag: &str) -> bool {
+    etag.len() == 32 && etag.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Removes the cache entry at `entry` along with its hash.
+fn remove(entry: &Path) -> io::Result<()> {
+    fs::remove_file(entry)?;
+    drop(fs::remove_file(Cache::hash_file(entry)));
+    Ok(())
+}
+
+/// Hard links `src` to `dst` so a cached artifact doesn't take up space
+/// twice, falling back to copying if that's not possible.
+fn link_or_copy(src: &Path, dst: &Path) -> io::Result<()> {
+    drop(fs::remove_file(dst));
+    if fs::hard_link(src, dst).is_err() {
+        fs::copy(src, dst)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("cache-{}-{}", name, process::id()));
+        drop(fs::remove_dir_all(&dir));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn reuses_entries() {
+        let dir = scratch("reuse");
+        let cache = Cache::new(&dir.join("cache"), 1 << 20).unwrap();
+        let download = dir.join("a.tar.xz");
+        fs::write(&download, "contents").unwrap();
+        // Multipart ETags aren't a plain hash, which doesn't matter.
+        cache.insert("0123-2", &download, 8, None).unwrap();
+        assert_eq!(fs::read_to_string(&download).unwrap(), "contents");
+
+        let dst = dir.join("b.tar.xz");
+        assert!(cache.get("0123-2", &dst).unwrap());
+        assert_eq!(fs::read_to_string(&dst).unwrap(), "contents");
+        assert!(!cache.get("4567", &dst).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_truncated_downloads() {
+        let dir = scratch("truncated");
+        let cache = Cache::new(&dir.join("cache"), 1 << 20).unwrap();
+        let download = dir.join("a.tar.xz");
+        fs::write(&download, "cont").unwrap();
+        assert!(cache.insert("0123", &download, 8, None).is_err());
+        assert!(!cache.get("0123", &dir.join("b.tar.xz")).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn discards_corrupt_entries() {
+        let dir = scratch("corrupt");
+        let cache = Cache::new(&dir.join("cache"), 1 << 20).unwrap();
+        let download = dir.join("a.tar.xz");
+        fs::write(&download, "contents").unwrap();
+        cache.insert("0123", &download, 8, Some(&hashes::sha256(b"contents"))).unwrap();
+        fs::remove_file(&download).unwrap();
+        fs::write(dir.join("cache/0123"), "corrupt!").unwrap();
+
+        assert!(!cache.get("0123", &dir.join("b.tar.xz")).unwrap());
+        assert!(!dir.join("cache/0123").exists());
+        assert!(!dir.join("cache/0123.sha256").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let dir = scratch("evict");
+        let cache = Cache::new(&dir.join("cache"), 10).unwrap();
+        for (i, etag) in ["old", "new"].iter().enumerate() {
+            let download = dir.join(etag);
+            fs::write(&download, "12345678").unwrap();
+            cache.insert(etag, &download, 8, None).unwrap();
+            let modified = SystemTime::UNIX_EPOCH + ::std::time::Duration::from_secs(i as u64);
+            File::options().write(true).open(dir.join("cache").join(etag)).unwrap()
+                .set_modified(modified).unwrap();
+        }
+        assert_eq!(cache.evict().unwrap(), 8);
+        assert!(!dir.join("cache/old").exists());
+        assert!(!dir.join("cache/old.sha256").exists());
+        assert!(dir.join("cache/new").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn checks_downloads_before_caching_them() {
+        let dir = scratch("check");
+        let cache = Cache::new(&dir.join("cache"), 1 << 20).unwrap();
+        let download = dir.join("a.tar.xz");
+        let md5 = "98bf7d8c15784f0a3d63204441e1e2aa";
+        fs::write(&download, "contents").unwrap();
+        assert!(cache.insert("00000000000000000000000000000000", &download, 8, None).is_err());
+        assert!(cache.insert(md5, &download, 8, Some(&hashes::sha256(b"other"))).is_err());
+        assert!(dir.join("cache").read_dir().unwrap().next().is_none());
+
+        cache.insert(md5, &download, 8, None).unwrap();
+        // A `.sha256` takes precedence over the ETag, which isn't an MD5 for
+        // every kind of encryption.
+        cache.insert("ffffffffffffffffffffffffffffffff", &download, 8,
+                     Some(&hashes::sha256(b"contents"))).unwrap();
+        assert!(cache.get(md5, &dir.join("b.tar.xz")).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
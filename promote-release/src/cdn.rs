@@ -0,0 +1,360 @@
+//! Purging of the CDNs sitting in front of the buckets we publish to.
+//!
+//! Each publication target (`static` for the dist artifacts and `docs` for
+//! the documentation) is configured with its own `[dist.cdn.<target>]` table
+//! in the secrets, whose `provider` key selects one of the implementations
+//! below.
+
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use toml::Value;
+
+use http::Client;
+
+pub type Result = ::std::result::Result<(), Box<dyn Error>>;
+
+/// A CDN whose cached copies of our objects can be purged.
+///
+/// Paths are always absolute URL paths on the CDN's domain, like
+/// `/dist/channel-rust-nightly.toml`.
+pub trait Cdn {
+    /// Purges exactly the listed `paths`.
+    fn purge_paths(&mut self, paths: &[String]) -> Result;
+
+    /// Purges everything matching `wildcard`, which is a path ending in `*`.
+    fn purge_wildcard(&mut self, wildcard: &str) -> Result;
+
+    /// Waits until everything purged so far is gone from the CDN's caches,
+    /// so that what it serves afterwards can be checked. Purges are done by
+    /// the time they return unless an implementation says otherwise.
+    fn wait(&mut self) -> Result {
+        Ok(())
+    }
+}
+
+/// The paths a CDN caches the file at `path` under: the path itself, and its
+/// directory's URL too if it's an `index.html`, which is cached separately.
+pub fn cached_paths(path: &str) -> Vec<&str> {
+    let mut paths = vec![path];
+    if let Some(dir) = path.strip_suffix("index.html") {
+        if dir.is_empty() || dir.ends_with('/') {
+            paths.push(dir);
+        }
+    }
+    paths
+}
+
+/// Creates the CDN configured by the `[dist.cdn.<target>]` table `config`.
+///
+/// Keys missing from `config` are looked up in `defaults` instead, which is
+/// how CloudFront picks up the AWS credentials in `[dist]`. `work` is a
+/// scratch directory implementations may write files to.
+pub fn from_config(config: &Value, defaults: &Value, work: &Path)
+    -> ::std::result::Result<Box<dyn Cdn>, String>
+{
+    let get = |key: &str| {
+        let value = config.get(key).or_else(|| defaults.get(key));
+        match value.and_then(|v| v.as_str()) {
+            Some(value) => Ok(value.to_string()),
+            None => Err(format!("missing string `{}` in CDN config", key)),
+        }
+    };
+    Ok(match &get("provider")?[..] {
+        "cloudfront" => Box::new(CloudFront {
+            distribution_id: get("distribution-id")?,
+            access_key: get("aws-access-key-id")?,
+            secret_key: get("aws-secret-key")?,
+            payload: work.join("payload.json"),
+            pending: Vec::new(),
+        }),
+        "fastly" => Box::new(Fastly {
+            http: Client::new(defaults),
+            service_id: get("service-id")?,
+            api_token: get("api-token")?,
+            domain: get("domain")?,
+            api: match config.get("api-url") {
+                Some(url) => url.as_str().ok_or("api-url not a string")?.to_string(),
+                None => "https://api.fastly.com".to_string(),
+            },
+        }),
+        "recording" => Box::new(Recording::new(PathBuf::from(get("path")?))),
+        other => return Err(format!("unknown CDN provider: {}", other)),
+    })
+}
+
+/// Number of paths submitted to CloudFront in a single invalidation.
+const CLOUDFRONT_BATCH: usize = 500;
+
+/// Amazon CloudFront, purged by creating invalidations through the `aws` CLI.
+///
+/// Invalidations take minutes to finish after they're created, which is what
+/// `wait` waits for.
+pub struct CloudFront {
+    pub distribution_id: String,
+    pub access_key: String,
+    pub secret_key: String,
+    pub payload: PathBuf,
+    /// IDs of the invalidations created and not waited for yet.
+    pub pending: Vec<String>,
+}
+
+impl CloudFront {
+    fn aws(&self) -> Command {
+        let mut cmd = Command::new("aws");
+        cmd.env("AWS_ACCESS_KEY_ID", &self.access_key)
+           .env("AWS_SECRET_ACCESS_KEY", &self.secret_key);
+        cmd
+    }
+
+    fn invalidate(&mut self, paths: &[String]) -> Result {
+        println!("invalidating {} paths in {}", paths.len(), self.distribution_id);
+        let json = json!({
+            "Paths": {
+                "Items": paths,
+                "Quantity": paths.len(),
+            },
+            "CallerReference": format!("rct-{}", ::rand::random::<usize>()),
+        }).to_string();
+        File::create(&self.payload)?.write_all(json.as_bytes())?;
+
+        let mut cmd = self.aws();
+        cmd.arg("cloudfront")
+           .arg("create-invalidation")
+           .arg("--invalidation-batch").arg(format!("file://{}", self.payload.display()))
+           .arg("--distribution-id").arg(&self.distribution_id)
+           .arg("--query").arg("Invalidation.Id")
+           .arg("--output").arg("text");
+        println!("running {:?}", cmd);
+        let output = cmd.output()?;
+        if !output.status.success() {
+            return Err(format!("failed command: {:?}: {}\n{}", cmd, output.status,
+                               String::from_utf8_lossy(&output.stderr)).into())
+        }
+        self.pending.push(String::from_utf8(output.stdout)?.trim().to_string());
+        Ok(())
+    }
+}
+
+impl Cdn for CloudFront {
+    fn purge_paths(&mut self, paths: &[String]) -> Result {
+        for batch in paths.chunks(CLOUDFRONT_BATCH) {
+            self.invalidate(batch)?;
+        }
+        Ok(())
+    }
+
+    fn purge_wildcard(&mut self, wildcard: &str) -> Result {
+        self.invalidate(&[wildcard.to_string()])
+    }
+
+    fn wait(&mut self) -> Result {
+        for id in ::std::mem::take(&mut self.pending) {
+            println!("waiting for invalidation {} of {}", id, self.distribution_id);
+            let mut cmd = self.aws();
+            cmd.arg("cloudfront")
+               .arg("wait")
+               .arg("invalidation-completed")
+               .arg("--distribution-id").arg(&self.distribution_id)
+               .arg("--id").arg(&id);
+            let status = cmd.status()?;
+            if !status.success() {
+                return Err(format!("failed command: {:?}: {}", cmd, status).into())
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Maximum number of surrogate keys Fastly accepts in a single purge.
+const FASTLY_KEY_BATCH: usize = 256;
+
+/// Fastly, purged through its HTTP API.
+///
+/// Individual paths are purged by URL. Fastly has no wildcard purges, so for
+/// those objects are tagged with surrogate keys naming the directories they
+/// live in (see `metadata::Policy::lookup`), and wildcards are turned into a
+/// purge of the key naming the innermost directory, like `dist` for
+/// `/dist/*`. `/*` purges the whole service.
+///
+/// S3 serves those keys as `x-amz-meta-surrogate-key`, which the service has
+/// to copy into `Surrogate-Key` for any of this to work.
+///
+/// Fastly's purges are done by the time the API answers them, so there's
+/// nothing to wait for. The API is called through the same HTTP client as
+/// everything else, with its timeouts, retries and proxy settings.
+pub struct Fastly {
+    pub http: Client,
+    pub service_id: String,
+    pub api_token: String,
+    pub domain: String,
+    /// Base URL of the API, `https://api.fastly.com` but for tests.
+    pub api: String,
+}
+
+impl Fastly {
+    fn post(&mut self, url: &str, headers: &[String]) -> Result {
+        let mut headers = headers.to_vec();
+        headers.push(format!("Fastly-Key: {}", self.api_token));
+        headers.push("Accept: application/json".to_string());
+        let response = self.http.post(url, &headers)?;
+        match response.code {
+            200 => Ok(()),
+            code => Err(format!("fastly purge of {} failed with {}: {}",
+                                url, code, String::from_utf8_lossy(&response.body)).into()),
+        }
+    }
+
+    /// Purges everything tagged with any of `keys`.
+    pub fn purge_keys(&mut self, keys: &[String]) -> Result {
+        for batch in keys.chunks(FASTLY_KEY_BATCH) {
+            println!("purging {} surrogate keys in {}", batch.len(), self.service_id);
+            let url = format!("{}/service/{}/purge", self.api, self.service_id);
+            self.post(&url, &[format!("Surrogate-Key: {}", batch.join(" "))])?;
+        }
+        Ok(())
+    }
+}
+
+impl Cdn for Fastly {
+    fn purge_paths(&mut self, paths: &[String]) -> Result {
+        println!("purging {} urls in {}", paths.len(), self.service_id);
+        for path in paths {
+            self.post(&format!("{}/purge/{}{}", self.api, self.domain, path), &[])?;
+        }
+        Ok(())
+    }
+
+    fn purge_wildcard(&mut self, wildcard: &str) -> Result {
+        let dir = wildcard.trim_end_matches('*').trim_matches('/');
+        if dir.is_empty() {
+            println!("purging everything in {}", self.service_id);
+            let url = format!("{}/service/{}/purge_all", self.api, self.service_id);
+            return self.post(&url, &[])
+        }
+        let key = dir.rsplit('/').next().unwrap();
+        self.purge_keys(&[key.to_string()])
+    }
+}
+
+/// A CDN that doesn't purge anything but appends every request it gets to a
+/// file, one per line, as `paths <path>...` or `wildcard <wildcard>`.
+///
+/// Useful for dry runs and for exercising the release flow without a real
+/// CDN in front of it.
+pub struct Recording {
+    path: PathBuf,
+}
+
+impl Recording {
+    pub fn new(path: PathBuf) -> Recording {
+        Recording { path }
+    }
+
+    fn record(&self, line: &str) -> Result {
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        writeln!(file, "{}", line)?;
+        Ok(())
+    }
+}
+
+impl Cdn for Recording {
+    fn purge_paths(&mut self, paths: &[String]) -> Result {
+        self.record(&format!("paths {}", paths.join(" ")))
+    }
+
+    fn purge_wildcard(&mut self, wildcard: &str) -> Result {
+        self.record(&format!("wildcard {}", wildcard))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use super::*;
+    use test_server::{Reply, Server};
+
+    fn config(toml: &str) -> Value {
+        toml.parse().unwrap()
+    }
+
+    #[test]
+    fn directory_urls_are_cached_separately() {
+        assert_eq!(cached_paths("std/index.html"), ["std/index.html", "std/"]);
+        assert_eq!(cached_paths("index.html"), ["index.html", ""]);
+        assert_eq!(cached_paths("std/vec/struct.Vec.html"), ["std/vec/struct.Vec.html"]);
+        assert_eq!(cached_paths("std/myindex.html"), ["std/myindex.html"]);
+    }
+
+    #[test]
+    fn recording_appends_requests() {
+        let path = env::temp_dir().join(format!("cdn-recording-{}", process::id()));
+        drop(fs::remove_file(&path));
+        let config = config(&format!("provider = \"recording\"\npath = {:?}", path));
+        let mut cdn = from_config(&config, &Value::Table(Default::default()), &env::temp_dir())
+            .unwrap();
+        cdn.purge_paths(&["/dist/a.toml".to_string(), "/dist/b.toml".to_string()]).unwrap();
+        cdn.purge_wildcard("/dist/*").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(),
+                   "paths /dist/a.toml /dist/b.toml\nwildcard /dist/*\n");
+        fs::remove_file(&path).unwrap();
+    }
+
+    fn fastly(server: &Server) -> Box<dyn Cdn> {
+        let fastly = config(&format!("provider = \"fastly\"\nservice-id = \"svc\"\n\
+                                      domain = \"static.example.com\"\napi-url = {:?}",
+                                     server.url));
+        let defaults = config("api-token = \"token\"");
+        from_config(&fastly, &defaults, &env::temp_dir()).unwrap()
+    }
+
+    #[test]
+    fn fastly_purges_paths_by_url() {
+        let server = Server::sequence(vec![Reply::Status(200, b"{}".to_vec())]);
+        let mut cdn = fastly(&server);
+        cdn.purge_paths(&["/dist/channel-rust-nightly.toml".to_string()]).unwrap();
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].path, "/purge/static.example.com/dist/channel-rust-nightly.toml");
+        assert_eq!(requests[0].header("fastly-key"), Some("token"));
+    }
+
+    #[test]
+    fn fastly_purges_wildcards_by_surrogate_key() {
+        let server = Server::sequence(vec![Reply::Status(200, b"{}".to_vec())]);
+        let mut cdn = fastly(&server);
+        cdn.purge_wildcard("/dist/*").unwrap();
+        cdn.purge_wildcard("/rust/dist/*").unwrap();
+        cdn.purge_wildcard("/*").unwrap();
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0].path, "/service/svc/purge");
+        assert_eq!(requests[0].header("surrogate-key"), Some("dist"));
+        assert_eq!(requests[1].header("surrogate-key"), Some("dist"));
+        assert_eq!(requests[2].path, "/service/svc/purge_all");
+    }
+
+    #[test]
+    fn fastly_reports_failures() {
+        let server = Server::sequence(vec![Reply::Status(403, b"denied".to_vec())]);
+        let err = fastly(&server).purge_wildcard("/dist/*").unwrap_err();
+        assert!(err.to_string().contains("denied"), "{}", err);
+    }
+
+    #[test]
+    fn fastly_retries_server_errors() {
+        let server = Server::sequence(vec![
+            Reply::Status(503, Vec::new()),
+            Reply::Status(200, b"{}".to_vec()),
+        ]);
+        fastly(&server).purge_paths(&["/dist/index.html".to_string()]).unwrap();
+        assert_eq!(server.requests().len(), 2);
+    }
+}
@@ -0,0 +1,108 @@
+//! Lists of the pull requests merged by bors between two revisions.
+
+use git2::{Error, Oid, Repository};
+
+use git;
+
+/// A pull request that was merged between two releases.
+pub struct Entry {
+    pub pr: u32,
+    pub title: String,
+    pub rev: String,
+}
+
+/// Collects all of bors's "Auto merge of #NNNN" merge commits that are on
+/// the first-parent history of `to` but not of `from`, newest first.
+pub fn collect(repo: &Repository, from: Oid, to: Oid) -> Result<Vec<Entry>, Error> {
+    Ok(git::first_parent_commits(repo, from, to)?.iter().filter_map(|commit| {
+        let message = commit.message()?;
+        let pr = message.strip_prefix("Auto merge of #")?;
+        let pr = pr[..pr.find(|c: char| !c.is_ascii_digit())?].parse().ok()?;
+
+        // bors puts the PR title on the first line after the summary.
+        let title = message.lines()
+            .skip(1)
+            .map(|l| l.trim())
+            .find(|l| !l.is_empty())
+            .unwrap_or("");
+        Some(Entry {
+            pr,
+            title: title.to_string(),
+            rev: commit.id().to_string(),
+        })
+    }).collect())
+}
+
+/// Renders `entries` as a JSON array of `{"pr", "title", "rev"}` objects.
+pub fn to_json(entries: &[Entry]) -> String {
+    let entries = entries.iter().map(|e| {
+        json!({
+            "pr": e.pr,
+            "title": e.title,
+            "rev": e.rev,
+        })
+    }).collect::<Vec<_>>();
+    serde_json::to_string_pretty(&entries).unwrap()
+}
+
+/// Renders `entries` as a Markdown list linking to each PR in `repo_url`.
+pub fn to_markdown(entries: &[Entry], from: &str, to: &str, repo_url: &str) -> String {
+    let mut md = format!("Pull requests merged between {} and {}\n\n", from, to);
+    for e in entries {
+        md.push_str(&format!("* [#{pr}]({url}/pull/{pr}) {title} ({rev})\n",
+                             pr = e.pr,
+                             url = repo_url,
+                             title = e.title,
+                             rev = &e.rev[..9]));
+    }
+    md
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use git2::Signature;
+
+    use super::*;
+
+    fn commit(repo: &Repository, message: &str, parents: &[Oid]) -> Oid {
+        let sig = Signature::new("bors", "bors@rust-lang.org", &git2::Time::new(0, 0)).unwrap();
+        let tree = repo.find_tree(repo.treebuilder(None).unwrap().write().unwrap()).unwrap();
+        let parents = parents.iter().map(|p| repo.find_commit(*p).unwrap()).collect::<Vec<_>>();
+        let parents = parents.iter().collect::<Vec<_>>();
+        repo.commit(None, &sig, &sig, message, &tree, &parents).unwrap()
+    }
+
+    #[test]
+    fn collects_bors_merges() {
+        let dir = env::temp_dir().join(format!("changelog-{}", process::id()));
+        drop(fs::remove_dir_all(&dir));
+        let repo = Repository::init_bare(&dir).unwrap();
+
+        let previous = commit(&repo, "Auto merge of #1 - a:b, r=c\n\nOld\n", &[]);
+        let feature = commit(&repo, "Add a feature\n", &[previous]);
+        let first = commit(&repo, "Auto merge of #12 - a:feature, r=c\n\n\
+                                   Add a feature\n\nDetails.\n", &[previous, feature]);
+        let other = commit(&repo, "Bump the version\n", &[first]);
+        let rollup = commit(&repo, "Rollup merge of #14 - a:fix, r=c\n", &[other]);
+        let last = commit(&repo, "Auto merge of #13 - a:rollup, r=c\n\n  Rollup of 2 pull \
+                                  requests\n", &[rollup]);
+
+        let entries = collect(&repo, previous, last).unwrap();
+        let found = entries.iter().map(|e| (e.pr, &e.title[..], &e.rev[..])).collect::<Vec<_>>();
+        assert_eq!(found, [
+            (13, "Rollup of 2 pull requests", &last.to_string()[..]),
+            (12, "Add a feature", &first.to_string()[..]),
+        ]);
+        assert!(collect(&repo, last, last).unwrap().is_empty());
+
+        let md = to_markdown(&entries[1..], "1.0.0", "2.0.0", "https://github.com/rust-lang/rust");
+        assert_eq!(md, format!("Pull requests merged between 1.0.0 and 2.0.0\n\n\
+                                * [#12](https://github.com/rust-lang/rust/pull/12) \
+                                Add a feature ({})\n", &first.to_string()[..9]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,433 @@
+//! Extraction of HTML documentation from the `rust-docs` and `rustc-docs`
+//! installer tarballs, and bundling of the extracted docs for offline use.
+//!
+//! Installer tarballs look like:
+//!
+//! ```text
+//! rust-docs-nightly-x86_64-unknown-linux-gnu/
+//!     components
+//!     rust-docs/
+//!         manifest.in
+//!         share/doc/rust/html/...
+//! ```
+//!
+//! where `manifest.in` lists what the component installs, one `file:<path>`
+//! or `dir:<path>` per line. Documentation is always installed as a single
+//! directory, which is what we look for rather than assuming where in the
+//! tarball it lives.
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+
+use flate2::read::GzDecoder;
+use tar::{Archive, Builder};
+use xz2::write::XzEncoder;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The tarball isn't laid out like an installer for the component.
+    Layout(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "{}", e),
+            Error::Layout(ref msg) => write!(f, "unexpected tarball layout: {}", msg),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+fn open(tarball: &Path) -> io::Result<Archive<GzDecoder<File>>> {
+    Ok(Archive::new(GzDecoder::new(File::open(tarball)?)))
+}
+
+/// Extracts the documentation installed by `component` from the installer
+/// `tarball` into `dst`, so that `dst` ends up containing the docs' root
+/// (e.g. `dst/std/index.html`).
+pub fn extract(tarball: &Path, component: &str, dst: &Path) -> Result<(), Error> {
+    let (prefix, root) = docs_root(tarball, component)?;
+    println!("extracting {} from {}", root.display(), tarball.display());
+
+    let mut extracted = 0;
+    for entry in open(tarball)?.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let relative = match path.strip_prefix(&prefix) {
+            Ok(relative) => relative.to_path_buf(),
+            Err(_) => continue,
+        };
+        if relative.components().any(|c| !matches!(c, Component::Normal(_))) {
+            return Err(Error::Layout(format!("suspicious path {}", path.display())))
+        }
+        if !entry.header().entry_type().is_file() {
+            continue
+        }
+        let dst = dst.join(relative);
+        fs::create_dir_all(dst.parent().unwrap())?;
+        entry.unpack(&dst)?;
+        extracted += 1;
+    }
+    if extracted == 0 {
+        return Err(Error::Layout(format!("no files under {}", prefix.display())))
+    }
+    Ok(())
+}
+
+/// Finds where in `tarball` the docs installed by `component` live.
+///
+/// Returns the path to them within the tarball, along with the path they're
+/// installed to.
+fn docs_root(tarball: &Path, component: &str) -> Result<(PathBuf, PathBuf), Error> {
+    let mut manifest = None;
+    for entry in open(tarball)?.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let mut components = path.iter();
+        let top = components.next();
+        if components.as_path() != Path::new(component).join("manifest.in") {
+            continue
+        }
+        let mut contents = String::new();
+        entry.read_to_string(&mut contents)?;
+        manifest = Some((PathBuf::from(top.unwrap()), contents));
+        break
+    }
+    let (top, contents) = match manifest {
+        Some(manifest) => manifest,
+        None => return Err(Error::Layout(format!("no {}/manifest.in in {}",
+                                                 component, tarball.display()))),
+    };
+
+    let dirs = contents.lines()
+        .filter_map(|l| l.strip_prefix("dir:"))
+        .collect::<Vec<_>>();
+    match dirs[..] {
+        [dir] => Ok((top.join(component).join(dir), PathBuf::from(dir))),
+        [] => Err(Error::Layout(format!("{} doesn't install any directory", component))),
+        _ => Err(Error::Layout(format!("{} installs several directories: {}",
+                                       component, dirs.join(", ")))),
+    }
+}
+
+/// Extracts the docs of each of `targets` from the installers for `version`
+/// in `dl` into `dst`.
+///
+/// The first target's docs go at the root of `dst`, with its rustc docs in
+/// `nightly-rustc` if there's an installer for them. The others go in a
+/// directory named after themselves, skipping targets without docs, and if
+/// there's more than one target the root gets a `targets.html` linking them.
+/// Returns the targets whose docs were extracted.
+pub fn extract_targets(dl: &Path, version: &str, channel: &str, targets: &[String],
+                       dst: &Path) -> Result<Vec<String>, Error> {
+    let tarball = |component: &str, target: &str| {
+        dl.join(format!("{}-{}-{}.tar.gz", component, version, target))
+    };
+    let host = &targets[0];
+    extract(&tarball("rust-docs", host), "rust-docs", dst)?;
+    let rustc = tarball("rustc-docs", host);
+    if rustc.exists() {
+        extract(&rustc, "rustc-docs", &dst.join("nightly-rustc"))?;
+    }
+
+    let mut published = vec![host.clone()];
+    for target in &targets[1..] {
+        let tarball = tarball("rust-docs", target);
+        if !tarball.exists() {
+            println!("no docs for {}, skipping", target);
+            continue
+        }
+        extract(&tarball, "rust-docs", &dst.join(target))?;
+        published.push(target.clone());
+    }
+    if targets.len() > 1 {
+        let published = published.iter().map(|t| &t[..]).collect::<Vec<_>>();
+        fs::write(dst.join("targets.html"), targets_index(channel, &published))?;
+    }
+    Ok(published)
+}
+
+/// Renders the page linking to the docs of each of `targets` published in
+/// the `channel` docs, where the first target is the one at the root.
+pub fn targets_index(channel: &str, targets: &[&str]) -> String {
+    let mut items = format!("<li><a href=\"index.html\">{}</a></li>\n", targets[0]);
+    for target in &targets[1..] {
+        items.push_str(&format!("<li><a href=\"{0}/index.html\">{0}</a></li>\n", target));
+    }
+    format!("<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<title>Rust {0} documentation by target</title>
+</head>
+<body>
+<h1>Rust {0} documentation by target</h1>
+<ul>
+{1}</ul>
+</body>
+</html>
+", channel, items)
+}
+
+/// Packs the docs tree in `docs` into the xz-compressed tarball `dst`, with
+/// everything inside a top-level directory called `name`, leaving out the
+/// top-level entries in `skip`.
+///
+/// The bundle is meant to hold the docs of the host target only, std and
+/// rustc alike, so the other targets' directories are skipped.
+pub fn bundle(docs: &Path, name: &str, dst: &Path, skip: &[&str]) -> io::Result<()> {
+    let mut builder = Builder::new(XzEncoder::new(File::create(dst)?, 6));
+    builder.follow_symlinks(false);
+    let mut entries = fs::read_dir(docs)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    builder.append_dir(name, docs)?;
+    for entry in entries {
+        if skip.iter().any(|s| entry.file_name() == **s) {
+            continue
+        }
+        let path = Path::new(name).join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            builder.append_dir_all(path, entry.path())?;
+        } else {
+            builder.append_path_with_name(entry.path(), path)?;
+        }
+    }
+    builder.into_inner()?.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use tar::Header;
+
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("docs-{}-{}", name, process::id()));
+        drop(fs::remove_dir_all(&dir));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Writes an installer tarball for `component` to `path`, with the given
+    /// `manifest.in` and `files` under the component's directory.
+    fn installer(path: &Path, component: &str, manifest: &str, files: &[(&str, &str)]) {
+        let mut builder = Builder::new(GzEncoder::new(Vec::new(), Compression::fast()));
+        let root = path.file_name().unwrap().to_str().unwrap().trim_end_matches(".tar.gz");
+        let mut add = |path: String, contents: &str| {
+            let mut header = Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, path, contents.as_bytes()).unwrap();
+        };
+        add(format!("{}/components", root), &format!("{}\n", component));
+        add(format!("{}/{}/manifest.in", root, component), manifest);
+        for &(file, contents) in files {
+            add(format!("{}/{}/{}", root, component, file), contents);
+        }
+        fs::write(path, builder.into_inner().unwrap().finish().unwrap()).unwrap();
+    }
+
+    /// Writes a `rust-docs` installer for `target` to `dl`, whose `index.html`
+    /// says which target it's for.
+    fn rust_docs(dl: &Path, target: &str) {
+        installer(&dl.join(format!("rust-docs-nightly-{}.tar.gz", target)), "rust-docs",
+                  "dir:share/doc/rust/html\n",
+                  &[("share/doc/rust/html/index.html", target),
+                    ("share/doc/rust/html/std/index.html", "std")]);
+    }
+
+    #[test]
+    fn extracts_either_layout() {
+        let dir = scratch("layouts");
+        // rustc docs used to be installed straight to `html`, and are now
+        // installed to `html/rustc`.
+        for &root in &["share/doc/rust/html", "share/doc/rust/html/rustc"] {
+            let tarball = dir.join("rustc-docs-nightly-x86_64-unknown-linux-gnu.tar.gz");
+            let index = format!("{}/index.html", root);
+            let source = format!("{}/src/lib.rs.html", root);
+            installer(&tarball, "rustc-docs", &format!("dir:{}\n", root),
+                      &[(&index, "rustc"), (&source, "source")]);
+            let dst = dir.join("docs");
+            drop(fs::remove_dir_all(&dst));
+            extract(&tarball, "rustc-docs", &dst).unwrap();
+            assert_eq!(fs::read_to_string(dst.join("index.html")).unwrap(), "rustc");
+            assert_eq!(fs::read_to_string(dst.join("src/lib.rs.html")).unwrap(), "source");
+            assert_eq!(docs_root(&tarball, "rustc-docs").unwrap(),
+                       (Path::new("rustc-docs-nightly-x86_64-unknown-linux-gnu/rustc-docs")
+                            .join(root),
+                        PathBuf::from(root)));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_unexpected_layouts() {
+        let dir = scratch("unexpected");
+        let tarball = dir.join("rust-docs-nightly-x86_64-unknown-linux-gnu.tar.gz");
+        let html = [("share/doc/rust/html/index.html", "")];
+        let error = |manifest: &str, files: &[(&str, &str)]| {
+            installer(&tarball, "rust-docs", manifest, files);
+            extract(&tarball, "rust-docs", &dir.join("docs")).unwrap_err().to_string()
+        };
+        assert_eq!(error("file:share/doc/rust/README.md\n", &html),
+                   "unexpected tarball layout: rust-docs doesn't install any directory");
+        assert_eq!(error("dir:share/doc/rust/html\ndir:share/doc/cargo\n", &html),
+                   "unexpected tarball layout: rust-docs installs several directories: \
+                    share/doc/rust/html, share/doc/cargo");
+        assert_eq!(error("dir:share/doc/rust/html\n", &[("share/doc/README.md", "")]),
+                   "unexpected tarball layout: no files under \
+                    rust-docs-nightly-x86_64-unknown-linux-gnu/rust-docs/share/doc/rust/html");
+
+        installer(&tarball, "rustc-docs", "dir:share/doc/rust/html\n", &html);
+        assert_eq!(extract(&tarball, "rust-docs", &dir.join("docs")).unwrap_err().to_string(),
+                   format!("unexpected tarball layout: no rust-docs/manifest.in in {}",
+                           tarball.display()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_paths_escaping_the_docs() {
+        let dir = scratch("escaping");
+        let tarball = dir.join("rust-docs-nightly-x86_64-unknown-linux-gnu.tar.gz");
+        installer(&tarball, "rust-docs", "dir:share/doc/rust/html\n",
+                  &[("share/doc/rust/html/index.html", "")]);
+        // The tar crate won't write a `..` in a path, so it's patched in.
+        let mut builder = Builder::new(GzEncoder::new(Vec::new(), Compression::fast()));
+        let mut archive = open(&tarball).unwrap();
+        for entry in archive.entries().unwrap() {
+            let entry = entry.unwrap();
+            let header = entry.header().clone();
+            builder.append(&header, entry).unwrap();
+        }
+        let path = b"rust-docs-nightly-x86_64-unknown-linux-gnu/rust-docs/\
+                     share/doc/rust/html/../../../../../escaped";
+        let mut header = Header::new_old();
+        header.as_old_mut().name[..path.len()].copy_from_slice(path);
+        header.set_size(0);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append(&header, &[][..]).unwrap();
+        fs::write(&tarball, builder.into_inner().unwrap().finish().unwrap()).unwrap();
+
+        let err = extract(&tarball, "rust-docs", &dir.join("docs")).unwrap_err();
+        assert!(err.to_string().starts_with("unexpected tarball layout: suspicious path"),
+                "{}", err);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn extracts_each_target() {
+        let dir = scratch("targets");
+        let (dl, dst) = (dir.join("dl"), dir.join("docs"));
+        fs::create_dir_all(&dl).unwrap();
+        rust_docs(&dl, "x86_64-unknown-linux-gnu");
+        rust_docs(&dl, "x86_64-apple-darwin");
+        installer(&dl.join("rustc-docs-nightly-x86_64-unknown-linux-gnu.tar.gz"), "rustc-docs",
+                  "dir:share/doc/rust/html/rustc\n",
+                  &[("share/doc/rust/html/rustc/index.html", "rustc")]);
+        let targets = ["x86_64-unknown-linux-gnu", "x86_64-pc-windows-msvc", "x86_64-apple-darwin"]
+            .iter().map(|t| t.to_string()).collect::<Vec<_>>();
+
+        let published = extract_targets(&dl, "nightly", "nightly", &targets, &dst).unwrap();
+        assert_eq!(published, ["x86_64-unknown-linux-gnu", "x86_64-apple-darwin"]);
+        let read = |path| fs::read_to_string(dst.join(path)).unwrap();
+        assert_eq!(read("index.html"), "x86_64-unknown-linux-gnu");
+        assert_eq!(read("std/index.html"), "std");
+        assert_eq!(read("nightly-rustc/index.html"), "rustc");
+        assert_eq!(read("x86_64-apple-darwin/index.html"), "x86_64-apple-darwin");
+        assert!(!dst.join("x86_64-pc-windows-msvc").exists());
+        assert!(!dst.join("x86_64-apple-darwin/nightly-rustc").exists());
+        assert_eq!(read("targets.html"),
+                   targets_index("nightly", &["x86_64-unknown-linux-gnu", "x86_64-apple-darwin"]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn extracts_a_single_target_without_an_index() {
+        let dir = scratch("single");
+        let (dl, dst) = (dir.join("dl"), dir.join("docs"));
+        fs::create_dir_all(&dl).unwrap();
+        rust_docs(&dl, "x86_64-unknown-linux-gnu");
+        let targets = ["x86_64-unknown-linux-gnu".to_string()];
+        let published = extract_targets(&dl, "nightly", "nightly", &targets, &dst).unwrap();
+        assert_eq!(published, ["x86_64-unknown-linux-gnu"]);
+        assert!(!dst.join("targets.html").exists());
+        assert!(!dst.join("nightly-rustc").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fails_without_the_primary_target() {
+        let dir = scratch("primary");
+        let targets = ["x86_64-unknown-linux-gnu".to_string()];
+        assert!(extract_targets(&dir, "nightly", "nightly", &targets, &dir.join("docs")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn renders_the_targets_index() {
+        assert_eq!(targets_index("beta", &["x86_64-unknown-linux-gnu", "x86_64-apple-darwin"]),
+                   "<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<title>Rust beta documentation by target</title>
+</head>
+<body>
+<h1>Rust beta documentation by target</h1>
+<ul>
+<li><a href=\"index.html\">x86_64-unknown-linux-gnu</a></li>
+<li><a href=\"x86_64-apple-darwin/index.html\">x86_64-apple-darwin</a></li>
+</ul>
+</body>
+</html>
+");
+    }
+
+    #[test]
+    fn bundles_the_host_docs() {
+        let dir = scratch("bundle");
+        let (dl, docs) = (dir.join("dl"), dir.join("docs"));
+        fs::create_dir_all(&dl).unwrap();
+        rust_docs(&dl, "x86_64-unknown-linux-gnu");
+        rust_docs(&dl, "x86_64-apple-darwin");
+        installer(&dl.join("rustc-docs-nightly-x86_64-unknown-linux-gnu.tar.gz"), "rustc-docs",
+                  "dir:share/doc/rust/html/rustc\n",
+                  &[("share/doc/rust/html/rustc/index.html", "rustc")]);
+        let targets = ["x86_64-unknown-linux-gnu".to_string(), "x86_64-apple-darwin".to_string()];
+        extract_targets(&dl, "nightly", "nightly", &targets, &docs).unwrap();
+
+        let bundle = dir.join("rust-docs-html-nightly.tar.xz");
+        super::bundle(&docs, "rust-docs-html-nightly", &bundle,
+                      &["x86_64-apple-darwin", "targets.html"]).unwrap();
+        let mut archive = Archive::new(xz2::read::XzDecoder::new(File::open(&bundle).unwrap()));
+        let mut files = archive.entries().unwrap()
+            .map(|e| e.unwrap())
+            .filter(|e| e.header().entry_type().is_file())
+            .map(|e| e.path().unwrap().to_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files, [
+            "rust-docs-html-nightly/index.html",
+            "rust-docs-html-nightly/nightly-rustc/index.html",
+            "rust-docs-html-nightly/std/index.html",
+        ]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,186 @@
+//! Downloads of many files at once, with a cap on the total bandwidth used
+//! and progress reported as it goes.
+
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use http::Client;
+
+/// How many times a file is downloaded before giving up on it, if it keeps
+/// failing the `check` passed to `all`.
+const ATTEMPTS: u32 = 3;
+
+/// How often progress is reported.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(30);
+
+pub struct Download {
+    pub url: String,
+    pub dst: PathBuf,
+    pub size: u64,
+}
+
+pub struct Options {
+    /// How many files are downloaded at the same time.
+    pub parallelism: usize,
+    /// Cap on the total download rate, in bytes per second, shared evenly
+    /// between the concurrent downloads.
+    pub max_rate: Option<u64>,
+}
+
+/// Downloads `files` with a client configured by `config` (the `dist` table
+/// of the secrets) for each of `options.parallelism` threads.
+///
+/// Each file is passed to `check` once it's downloaded, and downloaded again
+/// if that fails. Files that couldn't be downloaded don't stop the others
+/// from being downloaded, and are returned along with why they failed.
+pub fn all<F>(files: Vec<Download>, config: &toml::Value, options: &Options, check: F)
+    -> Vec<(Download, String)>
+    where F: Fn(&Download) -> Result<(), Box<dyn Error>> + Sync
+{
+    let total_files = files.len();
+    let total_bytes = files.iter().map(|f| f.size).sum::<u64>();
+    let parallelism = options.parallelism.max(1).min(total_files.max(1));
+    let queue = Mutex::new(files.into_iter());
+    let failed = Mutex::new(Vec::new());
+    let done_files = AtomicUsize::new(0);
+    let received = AtomicU64::new(0);
+    let start = Instant::now();
+
+    thread::scope(|s| {
+        let (queue, failed, done_files, received, check) =
+            (&queue, &failed, &done_files, &received, &check);
+        let (stop, stopped) = mpsc::channel::<()>();
+        s.spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(PROGRESS_INTERVAL) {
+                report(done_files.load(Ordering::Relaxed), total_files,
+                       received.load(Ordering::Relaxed), total_bytes, start.elapsed());
+            }
+        });
+
+        let workers = (0..parallelism).map(|_| {
+            s.spawn(move || {
+                let mut client = Client::new(config);
+                if let Some(rate) = options.max_rate {
+                    client.max_recv_speed((rate / parallelism as u64).max(1));
+                }
+                loop {
+                    let file = match queue.lock().unwrap().next() {
+                        Some(file) => file,
+                        None => break,
+                    };
+                    if let Err(e) = download(&mut client, &file, received, check) {
+                        println!("giving up on {}: {}", file.url, e);
+                        failed.lock().unwrap().push((file, e.to_string()));
+                    }
+                    done_files.fetch_add(1, Ordering::Relaxed);
+                }
+            })
+        }).collect::<Vec<_>>();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        drop(stop);
+    });
+
+    report(total_files, total_files, received.load(Ordering::Relaxed), total_bytes,
+           start.elapsed());
+    failed.into_inner().unwrap()
+}
+
+fn download<F>(client: &mut Client, file: &Download, received: &AtomicU64, check: &F)
+    -> Result<(), Box<dyn Error>>
+    where F: Fn(&Download) -> Result<(), Box<dyn Error>>
+{
+    let mut attempt = 1;
+    loop {
+        let result = client.download_counted(&file.url, &file.dst, received)
+            .and_then(|()| check(file));
+        let e = match result {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+        // Whatever the failed attempt left behind doesn't count as progress.
+        let partial = fs::metadata(&file.dst).map(|m| m.len()).unwrap_or(0);
+        received.fetch_sub(partial, Ordering::Relaxed);
+        if attempt == ATTEMPTS {
+            return Err(e)
+        }
+        println!("attempt {} of {} failed: {}", attempt, file.url, e);
+        attempt += 1;
+    }
+}
+
+fn report(done_files: usize, total_files: usize, received: u64, total_bytes: u64,
+          elapsed: Duration) {
+    const MIB: f64 = (1 << 20) as f64;
+    let rate = received as f64 / elapsed.as_secs_f64().max(1.0);
+    let eta = if done_files == total_files {
+        "done".to_string()
+    } else if rate > 0.0 {
+        let secs = total_bytes.saturating_sub(received) as f64 / rate;
+        format!("{}m{:02}s", secs as u64 / 60, secs as u64 % 60)
+    } else {
+        "unknown".to_string()
+    };
+    println!("downloaded {}/{} files, {:.0}/{:.0} MiB at {:.1} MiB/s, ETA {}",
+             done_files, total_files, received as f64 / MIB, total_bytes as f64 / MIB,
+             rate / MIB, eta);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+
+    use super::*;
+    use test_server::{Reply, Server};
+
+    #[test]
+    fn retries_files_failing_the_check_and_reports_failures() {
+        let dir = env::temp_dir().join(format!("download-{}", process::id()));
+        drop(fs::remove_dir_all(&dir));
+        fs::create_dir_all(&dir).unwrap();
+        // `b` is truncated the first time it's downloaded.
+        let served = Arc::new(AtomicUsize::new(0));
+        let server = Server::new(move |request| {
+            match &request.path[..] {
+                "/a" => Reply::Status(200, b"aaaa".to_vec()),
+                "/b" if served.fetch_add(1, Ordering::SeqCst) == 0 => {
+                    Reply::Status(200, b"bb".to_vec())
+                }
+                "/b" => Reply::Status(200, b"bbbb".to_vec()),
+                _ => Reply::Status(404, Vec::new()),
+            }
+        });
+        let files = ["a", "b", "c"].iter().map(|name| Download {
+            url: format!("{}/{}", server.url, name),
+            dst: dir.join(name),
+            size: 4,
+        }).collect();
+        let options = Options { parallelism: 2, max_rate: None };
+        let failed = all(files, &toml::Value::Table(Default::default()), &options, |d| {
+            match fs::metadata(&d.dst)?.len() {
+                4 => Ok(()),
+                len => Err(format!("{} is {} bytes", d.dst.display(), len).into()),
+            }
+        });
+
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0.url, format!("{}/c", server.url));
+        assert_eq!(failed[0].1, format!("failed to download {}/c: 404", server.url));
+        assert_eq!(fs::read(dir.join("a")).unwrap(), b"aaaa");
+        assert_eq!(fs::read(dir.join("b")).unwrap(), b"bbbb");
+        let requests = server.requests();
+        let count = |path| requests.iter().filter(|r| r.path == path).count();
+        assert_eq!((count("/a"), count("/b"), count("/c")), (1, 2, ATTEMPTS as usize));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
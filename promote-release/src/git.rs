@@ -0,0 +1,348 @@
+//! In-process git operations on our cached checkout of rust-lang/rust.
+//!
+//! Everything here goes through libgit2 rather than spawning `git`, and all
+//! failures are reported as `git2::Error` so callers can decide what to do
+//! with them.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use git2::build::CheckoutBuilder;
+use git2::{Commit, Error, FetchOptions, Oid, Repository};
+
+/// Opens the repository at `dir`, or initializes an empty one there if
+/// nothing exists yet, making sure its `origin` remote points at `url`.
+///
+/// Full clones left behind by older versions of this tool are thrown away so
+/// that we start over with a shallow one. Nothing is fetched here, see
+/// `fetch_branches` for that.
+pub fn open_or_init(dir: &Path, url: &str) -> Result<Repository, Error> {
+    if dir.join(".git").is_dir() {
+        let repo = Repository::open(dir)?;
+        if repo.is_shallow() {
+            repo.remote_set_url("origin", url)?;
+            return Ok(repo)
+        }
+        println!("removing full clone in {}", dir.display());
+        drop(repo);
+        if let Err(e) = fs::remove_dir_all(dir) {
+            return Err(Error::from_str(&format!("failed to remove {}: {}",
+                                                dir.display(), e)))
+        }
+    }
+    let repo = Repository::init(dir)?;
+    repo.remote("origin", url)?;
+    Ok(repo)
+}
+
+/// Fetches only the last `depth` commits of the listed `branches` from
+/// `origin`, updating the corresponding `refs/remotes/origin/*` refs.
+///
+/// Explicit refspecs are used so we never download the hundreds of other
+/// refs that rust-lang/rust has, and the fetch is shallow so only as much
+/// history as asked for comes along.
+pub fn fetch_branches(repo: &Repository, branches: &[&str], depth: i32)
+    -> Result<(), Error>
+{
+    let refspecs = branches.iter()
+        .map(|b| format!("+refs/heads/{0}:refs/remotes/origin/{0}", b))
+        .collect::<Vec<_>>();
+    let mut remote = repo.find_remote("origin")?;
+    let mut opts = FetchOptions::new();
+    opts.download_tags(git2::AutotagOption::None);
+    opts.depth(depth);
+    remote.fetch(&refspecs, Some(&mut opts), None)
+}
+
+/// Returns the commit that `origin/<branch>` currently points at.
+pub fn remote_branch_rev(repo: &Repository, branch: &str) -> Result<Oid, Error> {
+    let name = format!("refs/remotes/origin/{}", branch);
+    Ok(repo.find_reference(&name)?.peel_to_commit()?.id())
+}
+
+/// Returns the commit checked out in `repo`.
+pub fn head_rev(repo: &Repository) -> Result<Oid, Error> {
+    Ok(repo.head()?.peel_to_commit()?.id())
+}
+
+/// Deepens the fetched history of `branch` step by step until the commit
+/// `rev`, which may be abbreviated, is available locally.
+///
+/// Returns `None` if `rev` still can't be found once `depths` is exhausted,
+/// for example because it isn't an ancestor of `branch` at all.
+pub fn deepen_until(repo: &Repository, branch: &str, rev: &str, depths: &[i32])
+    -> Result<Option<Oid>, Error>
+{
+    for &depth in depths {
+        if let Ok(commit) = repo.revparse_single(rev).and_then(|o| o.peel_to_commit()) {
+            return Ok(Some(commit.id()))
+        }
+        println!("fetching {} commits of {} to find {}", depth, branch, rev);
+        fetch_branches(repo, &[branch], depth)?;
+    }
+    Ok(repo.revparse_single(rev).and_then(|o| o.peel_to_commit()).ok().map(|c| c.id()))
+}
+
+/// Returns the commits on the first-parent history of `to` that aren't
+/// reachable from `from`, newest first.
+pub fn first_parent_commits<'r>(repo: &'r Repository, from: Oid, to: Oid)
+    -> Result<Vec<Commit<'r>>, Error>
+{
+    let mut walk = repo.revwalk()?;
+    walk.simplify_first_parent()?;
+    walk.push(to)?;
+    walk.hide(from)?;
+    walk.map(|id| repo.find_commit(id?)).collect()
+}
+
+/// Reads the contents of the file at `path` in the tree of `rev`, whether or
+/// not it's part of the sparse checkout.
+pub fn read_file(repo: &Repository, rev: &str, path: &str) -> Result<Vec<u8>, Error> {
+    let tree = repo.find_commit(Oid::from_str(rev)?)?.tree()?;
+    let blob = tree.get_path(Path::new(path))?.to_object(repo)?.peel_to_blob()?;
+    Ok(blob.content().to_vec())
+}
+
+/// Sparse equivalent of `git reset --hard <rev>`: forcibly updates the
+/// working tree to match `rev`, but only writes out files matching one of
+/// the `paths` pathspecs.
+///
+/// The working tree is emptied first, so nothing from a previous checkout
+/// with a different set of paths is left behind.
+pub fn sparse_checkout(repo: &Repository, rev: &str, paths: &[String])
+    -> Result<(), Error>
+{
+    let commit = repo.find_commit(Oid::from_str(rev)?)?;
+    let workdir = repo.workdir().ok_or_else(|| Error::from_str("bare repository"))?;
+    if let Err(e) = clear_workdir(workdir) {
+        return Err(Error::from_str(&format!("failed to clear {}: {}",
+                                            workdir.display(), e)))
+    }
+    let mut checkout = CheckoutBuilder::new();
+    checkout.force();
+    for path in paths {
+        checkout.path(path);
+    }
+    let mut index = repo.index()?;
+    index.clear()?;
+    index.write()?;
+    repo.checkout_tree(commit.as_object(), Some(&mut checkout))?;
+    repo.set_head_detached(commit.id())
+}
+
+/// Checks out the submodules whose gitlinks `sparse_checkout` wrote out, at
+/// the commits recorded for them, which needs `.gitmodules` checked out too.
+///
+/// Each submodule is cloned into `.git/modules` the first time like git
+/// does, so that it survives the working tree being cleared, and after that
+/// only fetched if the commit it's at is new.
+pub fn update_submodules(repo: &Repository) -> Result<(), Error> {
+    let workdir = repo.workdir().ok_or_else(|| Error::from_str("bare repository"))?;
+    for mut submodule in repo.submodules()? {
+        let id = match submodule.index_id() {
+            Some(id) => id,
+            None => continue,
+        };
+        let path = workdir.join(submodule.path());
+        let name = submodule.name().ok_or_else(|| Error::from_str("submodule name not utf-8"))?;
+        let gitdir = repo.path().join("modules").join(name);
+        if !gitdir.exists() {
+            println!("cloning submodule {}", submodule.path().display());
+            submodule.update(true, None)?;
+            continue
+        }
+        let sub = Repository::open(&gitdir)?;
+        if let Err(e) = fs::create_dir_all(&path) {
+            return Err(Error::from_str(&format!("failed to create {}: {}",
+                                                path.display(), e)))
+        }
+        sub.set_workdir(&path, true)?;
+        if sub.find_commit(id).is_err() {
+            println!("fetching submodule {}", submodule.path().display());
+            let mut remote = sub.find_remote("origin")?;
+            remote.fetch(&["+refs/heads/*:refs/remotes/origin/*"], None, None)?;
+        }
+        let commit = sub.find_commit(id)?;
+        sub.checkout_tree(commit.as_object(), Some(CheckoutBuilder::new().force()))?;
+        sub.set_head_detached(id)?;
+    }
+    Ok(())
+}
+
+fn clear_workdir(dir: &Path) -> io::Result<()> {
+    for entry in dir.read_dir()? {
+        let entry = entry?;
+        if entry.file_name() == ".git" {
+            continue
+        }
+        if entry.file_type()?.is_dir() {
+            fs::remove_dir_all(entry.path())?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::net::{TcpListener, TcpStream};
+    use std::path::PathBuf;
+    use std::process::{self, Child, Command, Stdio};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    /// A `git daemon` serving the repositories in a directory, since libgit2
+    /// can't do shallow fetches from local paths.
+    struct Daemon {
+        child: Child,
+        url: String,
+    }
+
+    impl Daemon {
+        fn new(dir: &Path) -> Daemon {
+            let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+            // Run directly rather than through `git daemon`, which would
+            // leave it running once the `git` we spawned is killed.
+            let exec_path = Command::new("git").arg("--exec-path").output().unwrap().stdout;
+            let exec_path = PathBuf::from(String::from_utf8(exec_path).unwrap().trim());
+            let child = Command::new(exec_path.join("git-daemon"))
+                .arg("--export-all").arg("--reuseaddr")
+                .arg("--listen=127.0.0.1").arg(format!("--port={}", port))
+                .arg(format!("--base-path={}", dir.display()))
+                .stdout(Stdio::null()).stderr(Stdio::null())
+                .spawn().unwrap();
+            for _ in 0..100 {
+                if TcpStream::connect(("127.0.0.1", port)).is_ok() {
+                    break
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+            Daemon { child, url: format!("git://127.0.0.1:{}", port) }
+        }
+    }
+
+    impl Drop for Daemon {
+        fn drop(&mut self) {
+            drop(self.child.kill());
+            drop(self.child.wait());
+        }
+    }
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("git-{}-{}", name, process::id()));
+        drop(fs::remove_dir_all(&dir));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn git(dir: &Path, args: &[&str]) -> String {
+        let output = Command::new("git").arg("-C").arg(dir)
+            .args(&["-c", "user.name=Test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .output().unwrap();
+        assert!(output.status.success(), "git {:?}: {}", args,
+                String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    }
+
+    /// Creates a repository in `dir` with the `files`, committed as one
+    /// commit, and returns the commit's id.
+    fn commit(dir: &Path, files: &[(&str, &str)]) -> String {
+        if !dir.join(".git").exists() {
+            fs::create_dir_all(dir).unwrap();
+            git(dir, &["init", "-q", "-b", "master"]);
+        }
+        for &(path, contents) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        git(dir, &["add", "."]);
+        git(dir, &["commit", "-q", "--allow-empty", "-m", "commit"]);
+        git(dir, &["rev-parse", "HEAD"])
+    }
+
+    /// An upstream repository with a couple of files and two submodules, and
+    /// a `history` of commits on top.
+    fn upstream(dir: &Path, history: usize) -> PathBuf {
+        let mut gitmodules = String::new();
+        for name in &["used", "unused"] {
+            let sub = dir.join(name);
+            commit(&sub, &[("Cargo.toml", name)]);
+            gitmodules.push_str(&format!("[submodule \"tools/{0}\"]\n\
+                                          \tpath = tools/{0}\n\
+                                          \turl = {1}\n",
+                                         name, sub.display()));
+        }
+        let upstream = dir.join("upstream");
+        commit(&upstream, &[
+            ("x.py", "x"),
+            ("src/bootstrap/lib.rs", "bootstrap"),
+            ("src/librustc/lib.rs", "rustc"),
+            (".gitmodules", &gitmodules),
+        ]);
+        for name in &["used", "unused"] {
+            let id = git(&dir.join(name), &["rev-parse", "HEAD"]);
+            git(&upstream, &["update-index", "--add", "--cacheinfo",
+                             &format!("160000,{},tools/{}", id, name)]);
+        }
+        git(&upstream, &["commit", "-q", "-m", "submodules"]);
+        for i in 0..history {
+            commit(&upstream, &[("x.py", &i.to_string())]);
+        }
+        upstream
+    }
+
+    #[test]
+    fn checks_out_only_the_sparse_paths() {
+        let dir = scratch("sparse");
+        upstream(&dir, 0);
+        let daemon = Daemon::new(&dir);
+        let checkout = dir.join("checkout");
+        let repo = open_or_init(&checkout, &format!("{}/upstream", daemon.url)).unwrap();
+        fetch_branches(&repo, &["master"], 1).unwrap();
+        let rev = remote_branch_rev(&repo, "master").unwrap().to_string();
+        let paths = ["x.py", "src/bootstrap", ".gitmodules", "tools/used"]
+            .iter().map(|p| p.to_string()).collect::<Vec<_>>();
+
+        // A second checkout starts from scratch but reuses the submodule.
+        for _ in 0..2 {
+            fs::write(checkout.join("stale"), "").unwrap();
+            sparse_checkout(&repo, &rev, &paths).unwrap();
+            update_submodules(&repo).unwrap();
+            assert!(checkout.join("x.py").exists());
+            assert!(checkout.join("src/bootstrap/lib.rs").exists());
+            assert!(!checkout.join("src/librustc").exists());
+            assert!(!checkout.join("stale").exists());
+            assert_eq!(fs::read_to_string(checkout.join("tools/used/Cargo.toml")).unwrap(),
+                       "used");
+            assert!(!checkout.join("tools/unused/Cargo.toml").exists());
+        }
+        assert_eq!(head_rev(&repo).unwrap().to_string(), rev);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn deepens_until_a_rev_is_found() {
+        let dir = scratch("deepen");
+        let upstream = upstream(&dir, 5);
+        let old = git(&upstream, &["rev-parse", "HEAD~3"]);
+        let daemon = Daemon::new(&dir);
+        let url = format!("{}/upstream", daemon.url);
+        let repo = open_or_init(&dir.join("checkout"), &url).unwrap();
+        fetch_branches(&repo, &["master"], 1).unwrap();
+        assert!(repo.is_shallow());
+        assert!(repo.revparse_single(&old).is_err());
+
+        let found = deepen_until(&repo, "master", &old[..10], &[2, 4, 8]).unwrap();
+        assert_eq!(found.unwrap().to_string(), old);
+        let missing = "0123456789012345678901234567890123456789";
+        assert_eq!(deepen_until(&repo, "master", missing, &[2, 4]).unwrap(), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,191 @@
+//! Detached signatures made with the release key, and their verification.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Verifies signatures against a keyring containing only the release key,
+/// so nothing else that happens to be in the local GnuPG home is trusted.
+pub struct Verifier {
+    keyring: PathBuf,
+}
+
+impl Verifier {
+    /// Creates a verifier trusting the ASCII-armored public key in
+    /// `public_key`, keeping its keyring in `dir`.
+    pub fn new(public_key: &Path, dir: &Path) -> io::Result<Verifier> {
+        fs::create_dir_all(dir)?;
+        let keyring = dir.join("release-keyring.gpg");
+        drop(fs::remove_file(&keyring));
+        let status = Command::new("gpg")
+            .arg("--batch")
+            .arg("--yes")
+            .arg("--output").arg(&keyring)
+            .arg("--dearmor").arg(public_key)
+            .status()?;
+        if !status.success() {
+            return Err(io::Error::other(
+                format!("failed to import {}: {}", public_key.display(), status)))
+        }
+        Ok(Verifier { keyring })
+    }
+
+    /// Creates a verifier trusting the keys whose secret halves are in the
+    /// local GnuPG home, which is what releases are signed with, keeping its
+    /// keyring in `dir`.
+    pub fn from_secret_keys(dir: &Path) -> io::Result<Verifier> {
+        let output = Command::new("gpg")
+            .arg("--batch")
+            .arg("--list-secret-keys")
+            .arg("--with-colons")
+            .output()?;
+        // Each `sec` line is followed by an `fpr` line with the fingerprint
+        // in its tenth field, and so are the subkeys, which aren't needed.
+        let listing = String::from_utf8_lossy(&output.stdout);
+        let mut fingerprints = Vec::new();
+        let mut lines = listing.lines();
+        while let Some(line) = lines.next() {
+            if line.starts_with("sec:") {
+                if let Some(fpr) = lines.next().and_then(|l| l.split(':').nth(9)) {
+                    fingerprints.push(fpr.to_string());
+                }
+            }
+        }
+        if fingerprints.is_empty() {
+            return Err(io::Error::other("no secret keys in the GnuPG home to verify with"))
+        }
+
+        fs::create_dir_all(dir)?;
+        let keyring = dir.join("release-keyring.gpg");
+        drop(fs::remove_file(&keyring));
+        let status = Command::new("gpg")
+            .arg("--batch")
+            .arg("--yes")
+            .arg("--output").arg(&keyring)
+            .arg("--export").args(&fingerprints)
+            .status()?;
+        if !status.success() {
+            return Err(io::Error::other(format!("failed to export the secret keys' public \
+                                                 halves: {}", status)))
+        }
+        Ok(Verifier { keyring })
+    }
+
+    /// Returns whether `signature` is a valid signature of `data` by the
+    /// release key.
+    pub fn verify(&self, data: &Path, signature: &Path) -> io::Result<bool> {
+        let output = Command::new("gpgv")
+            .arg("--keyring").arg(&self.keyring)
+            .arg(signature)
+            .arg(data)
+            .output()?;
+        if !output.status.success() {
+            println!("bad signature for {}:\n{}", data.display(),
+                     String::from_utf8_lossy(&output.stderr));
+        }
+        Ok(output.status.success())
+    }
+}
+
+/// The command that signs `file` with the default key of the local GnuPG
+/// home, unlocked with the passphrase in `password_file`, writing an
+/// ASCII-armored detached signature to `signature`.
+pub fn detach_sign(file: &Path, signature: &Path, password_file: &Path) -> Command {
+    let mut cmd = Command::new("gpg");
+    cmd.arg("--batch")
+       .arg("--no-tty")
+       .arg("--yes")
+       .arg("--pinentry-mode").arg("loopback")
+       .arg("--passphrase-file").arg(password_file)
+       .arg("--armor")
+       .arg("--output").arg(signature)
+       .arg("--detach-sign").arg(file);
+    cmd
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::env;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process;
+
+    use super::*;
+
+    fn gpg(home: &Path) -> Command {
+        let mut cmd = Command::new("gpg");
+        cmd.arg("--homedir").arg(home).arg("--batch").arg("--yes").arg("--quiet");
+        cmd
+    }
+
+    /// Creates a throwaway release key in a GnuPG home under `dir`, and
+    /// returns that home along with a verifier trusting the key.
+    pub fn keygen(dir: &Path) -> (PathBuf, Verifier) {
+        let home = dir.join("gnupg");
+        fs::create_dir_all(&home).unwrap();
+        fs::set_permissions(&home, fs::Permissions::from_mode(0o700)).unwrap();
+        let status = gpg(&home)
+            .arg("--passphrase").arg("")
+            .arg("--quick-gen-key").arg("Test Release <release@example.com>")
+            .arg("ed25519").arg("sign").arg("never")
+            .status().unwrap();
+        assert!(status.success());
+        let public = dir.join("release.pub");
+        assert!(gpg(&home).arg("--armor").arg("--output").arg(&public).arg("--export")
+                    .status().unwrap().success());
+        let verifier = Verifier::new(&public, &dir.join("keyring")).unwrap();
+        (home, verifier)
+    }
+
+    /// A detached signature of `data` by the key in `home`, using `dir` as
+    /// scratch space.
+    pub fn sign(home: &Path, data: &[u8], dir: &Path) -> Vec<u8> {
+        let path = dir.join("to-sign");
+        fs::write(&path, data).unwrap();
+        let signature = dir.join("to-sign.asc");
+        assert!(gpg(home).arg("--armor").arg("--detach-sign").arg("--output").arg(&signature)
+                    .arg(&path).status().unwrap().success());
+        fs::read(&signature).unwrap()
+    }
+
+    #[test]
+    fn verifies_signatures_by_the_release_key() {
+        let dir = env::temp_dir().join(format!("gpg-verify-{}", process::id()));
+        drop(fs::remove_dir_all(&dir));
+        fs::create_dir_all(&dir).unwrap();
+        let (home, verifier) = keygen(&dir);
+        let (other, _) = keygen(&dir.join("other"));
+
+        let data = dir.join("data");
+        let signature = dir.join("data.asc");
+        fs::write(&data, "release").unwrap();
+        fs::write(&signature, sign(&home, b"release", &dir)).unwrap();
+        assert!(verifier.verify(&data, &signature).unwrap());
+        fs::write(&data, "tampered").unwrap();
+        assert!(!verifier.verify(&data, &signature).unwrap());
+        fs::write(&data, "release").unwrap();
+        fs::write(&signature, sign(&other, b"release", &dir)).unwrap();
+        assert!(!verifier.verify(&data, &signature).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn makes_detached_signatures() {
+        let dir = env::temp_dir().join(format!("gpg-sign-{}", process::id()));
+        drop(fs::remove_dir_all(&dir));
+        fs::create_dir_all(&dir).unwrap();
+        let (home, verifier) = keygen(&dir);
+        let (data, signature) = (dir.join("SHA256SUMS"), dir.join("SHA256SUMS.asc"));
+        fs::write(&data, "0123  rust-1.0.0.tar.xz\n").unwrap();
+        fs::write(dir.join("password"), "").unwrap();
+
+        let status = detach_sign(&data, &signature, &dir.join("password"))
+            .env("GNUPGHOME", &home)
+            .status().unwrap();
+        assert!(status.success());
+        assert!(fs::read_to_string(&signature).unwrap()
+                    .starts_with("-----BEGIN PGP SIGNATURE-----"));
+        assert!(verifier.verify(&data, &signature).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
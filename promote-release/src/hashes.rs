@@ -0,0 +1,210 @@
+//! Content hashes of published trees of files, used to only upload what
+//! actually changed since the last time a tree was published.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+/// Maps paths relative to the root of a tree, always `/`-separated, to the
+/// hex SHA-256 of the file's contents.
+pub type Manifest = BTreeMap<String, String>;
+
+/// Returns the hex SHA-256 of `data`.
+pub fn sha256(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// Returns the hex SHA-256 of the contents of the file at `path`.
+pub fn sha256_file(path: &Path) -> io::Result<String> {
+    hash_file::<Sha256>(path)
+}
+
+/// Returns the hex MD5 of the contents of the file at `path`, which is what
+/// S3 uses as the ETag of objects uploaded in one part.
+pub fn md5_file(path: &Path) -> io::Result<String> {
+    hash_file::<Md5>(path)
+}
+
+fn hash_file<D: Digest>(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = D::new();
+    let mut buf = [0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Renders a `SHA256SUMS` file, in the format `sha256sum` produces and
+/// `sha256sum -c` checks, covering the files in `dir` for which `include`
+/// returns true.
+pub fn sha256sums<F>(dir: &Path, include: F) -> io::Result<String>
+    where F: Fn(&str) -> bool
+{
+    let mut names = Vec::new();
+    for entry in dir.read_dir()? {
+        let entry = entry?;
+        let name = entry.file_name().into_string().expect("non-utf8 file name");
+        if entry.file_type()?.is_file() && include(&name) {
+            names.push(name);
+        }
+    }
+    names.sort();
+    let mut sums = String::new();
+    for name in names {
+        sums.push_str(&format!("{}  {}\n", sha256_file(&dir.join(&name))?, name));
+    }
+    Ok(sums)
+}
+
+/// Parses the contents of a `SHA256SUMS` file into the hash of each file it
+/// lists.
+pub fn parse_sums(sums: &str) -> BTreeMap<String, String> {
+    sums.lines().filter_map(|line| {
+        let (hash, name) = line.split_once("  ")?;
+        Some((name.to_string(), hash.to_string()))
+    }).collect()
+}
+
+/// Lists the paths of all the files under `root`, relative to it,
+/// `/`-separated and sorted.
+pub fn files(root: &Path) -> io::Result<Vec<String>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in dir.read_dir()? {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                dirs.push(path);
+                continue
+            }
+            let relative = path.strip_prefix(root).unwrap()
+                .iter()
+                .map(|c| c.to_str().expect("non-utf8 path"))
+                .collect::<Vec<_>>()
+                .join("/");
+            files.push(relative);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Hashes every file under `root`.
+pub fn hash_tree(root: &Path) -> io::Result<Manifest> {
+    let mut manifest = Manifest::new();
+    for file in files(root)? {
+        let hash = sha256_file(&root.join(&file))?;
+        manifest.insert(file, hash);
+    }
+    Ok(manifest)
+}
+
+pub fn to_json(manifest: &Manifest) -> String {
+    serde_json::to_string(manifest).unwrap()
+}
+
+pub fn from_json(json: &str) -> serde_json::Result<Manifest> {
+    serde_json::from_str(json)
+}
+
+/// What changed between two manifests of the same tree.
+pub struct Diff {
+    /// Paths that are new or whose contents changed.
+    pub changed: Vec<String>,
+    /// Paths that no longer exist.
+    pub removed: Vec<String>,
+}
+
+pub fn diff(old: &Manifest, new: &Manifest) -> Diff {
+    Diff {
+        changed: new.iter()
+            .filter(|&(path, hash)| old.get(path) != Some(hash))
+            .map(|(path, _)| path.clone())
+            .collect(),
+        removed: old.keys()
+            .filter(|path| !new.contains_key(*path))
+            .cloned()
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use super::*;
+
+    fn manifest(entries: &[(&str, &str)]) -> Manifest {
+        entries.iter().map(|&(p, h)| (p.to_string(), h.to_string())).collect()
+    }
+
+    #[test]
+    fn hashes_trees() {
+        let dir = env::temp_dir().join(format!("hashes-tree-{}", process::id()));
+        drop(fs::remove_dir_all(&dir));
+        fs::create_dir_all(dir.join("std/vec")).unwrap();
+        fs::write(dir.join("index.html"), "").unwrap();
+        fs::write(dir.join("std/vec/index.html"), "hello").unwrap();
+        assert_eq!(hash_tree(&dir).unwrap(), manifest(&[
+            ("index.html", "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"),
+            ("std/vec/index.html",
+             "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"),
+        ]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn renders_sha256sums() {
+        let dir = env::temp_dir().join(format!("hashes-sums-{}", process::id()));
+        drop(fs::remove_dir_all(&dir));
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        for name in &["rust-1.0.0.tar.xz", "cargo-1.0.0.tar.gz", "cargo-1.0.0.tar.gz.sha256",
+                      "nested/rustc.tar.xz"] {
+            fs::write(dir.join(name), "hello").unwrap();
+        }
+        let hello = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        assert_eq!(sha256sums(&dir, |name| !name.ends_with(".sha256")).unwrap(),
+                   format!("{0}  cargo-1.0.0.tar.gz\n{0}  rust-1.0.0.tar.xz\n", hello));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parses_sha256sums() {
+        let sums = parse_sums("00  a.tar.xz\n11  b c.tar.gz\ngarbage\n");
+        assert_eq!(sums, manifest(&[("a.tar.xz", "00"), ("b c.tar.gz", "11")]));
+    }
+
+    #[test]
+    fn diffs_manifests() {
+        let old = manifest(&[("a.html", "1"), ("b.html", "2"), ("c.html", "3")]);
+        let new = manifest(&[("a.html", "1"), ("b.html", "4"), ("d.html", "5")]);
+        let changes = diff(&old, &new);
+        assert_eq!(changes.changed, ["b.html", "d.html"]);
+        assert_eq!(changes.removed, ["c.html"]);
+
+        // Without a previous manifest everything is new.
+        let changes = diff(&Manifest::new(), &new);
+        assert_eq!(changes.changed, ["a.html", "b.html", "d.html"]);
+        assert!(changes.removed.is_empty());
+    }
+
+    #[test]
+    fn round_trips_manifests_through_json() {
+        let manifest = manifest(&[("a.html", "1"), ("std/b.html", "2")]);
+        assert_eq!(from_json(&to_json(&manifest)).unwrap(), manifest);
+        assert!(from_json("{\"a.html\": ").is_err());
+        assert!(from_json("[\"a.html\"]").is_err());
+    }
+}
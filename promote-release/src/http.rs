@@ -0,0 +1,409 @@
+//! The HTTP client everything promote-release downloads or checks goes
+//! through, with timeouts and retries so a flaky connection to the CDN
+//! doesn't fail (or hang) a whole release.
+
+use std::env;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use curl::easy::{Easy, List};
+
+pub type Result<T> = ::std::result::Result<T, Box<dyn Error>>;
+
+/// Delay before the first retry, doubled for each one after that.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+pub struct Client {
+    handle: Easy,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    retries: u32,
+    backoff: Duration,
+    max_recv_speed: Option<u64>,
+}
+
+pub struct Response {
+    pub code: u32,
+    pub body: Vec<u8>,
+}
+
+impl Client {
+    /// Creates a client configured by the `http-connect-timeout`,
+    /// `http-read-timeout` (both in seconds) and `http-retries` keys of the
+    /// `dist` table of the secrets.
+    pub fn new(config: &toml::Value) -> Client {
+        let secs = |key, default| {
+            let secs = config.get(key)
+                .map(|s| s.as_integer().expect("http timeout not an integer"))
+                .unwrap_or(default);
+            Duration::from_secs(secs as u64)
+        };
+        Client {
+            handle: Easy::new(),
+            connect_timeout: secs("http-connect-timeout", 30),
+            read_timeout: secs("http-read-timeout", 60),
+            retries: config.get("http-retries")
+                .map(|r| r.as_integer().expect("http-retries not an integer") as u32)
+                .unwrap_or(5),
+            backoff: INITIAL_BACKOFF,
+            max_recv_speed: None,
+        }
+    }
+
+    /// Caps how fast this client receives data, in bytes per second.
+    pub fn max_recv_speed(&mut self, bytes_per_sec: u64) {
+        self.max_recv_speed = Some(bytes_per_sec);
+    }
+
+    /// Fetches `url` into memory. Any response that isn't a 5xx is returned
+    /// as-is for the caller to interpret.
+    pub fn get(&mut self, url: &str) -> Result<Response> {
+        let mut body = Vec::new();
+        let code = self.request("GET", url, &[], &mut body)?;
+        Ok(Response { code, body })
+    }
+
+    /// Sends an empty `POST` to `url` with the extra `headers`, each like
+    /// `Name: value`. Like for `get`, anything but a 5xx is returned as-is,
+    /// so the request had better be safe to repeat.
+    pub fn post(&mut self, url: &str, headers: &[String]) -> Result<Response> {
+        let mut body = Vec::new();
+        let code = self.request("POST", url, headers, &mut body)?;
+        Ok(Response { code, body })
+    }
+
+    /// Fetches `url`, failing unless the response is a 200.
+    pub fn get_ok(&mut self, url: &str) -> Result<Vec<u8>> {
+        let response = self.get(url)?;
+        match response.code {
+            200 => Ok(response.body),
+            code => Err(format!("failed to download {}: {}", url, code).into()),
+        }
+    }
+
+    /// Returns the status code of a `HEAD` request of `url`.
+    pub fn head(&mut self, url: &str) -> Result<u32> {
+        self.request("HEAD", url, &[], &mut io::sink())
+    }
+
+    /// Downloads `url` to `dst`, failing unless the response is a 200.
+    pub fn download(&mut self, url: &str, dst: &Path) -> Result<()> {
+        self.download_counted(url, dst, &AtomicU64::new(0))
+    }
+
+    /// Like `download`, also keeping a running count of the bytes received
+    /// in `received` for progress reporting.
+    pub fn download_counted(&mut self, url: &str, dst: &Path, received: &AtomicU64)
+        -> Result<()>
+    {
+        let mut sink = Counting {
+            inner: File::create(dst)?,
+            received,
+            written: 0,
+        };
+        let code = self.request("GET", url, &[], &mut sink)?;
+        match code {
+            200 => Ok(()),
+            code => Err(format!("failed to download {}: {}", url, code).into()),
+        }
+    }
+
+    /// Performs a request, retrying with exponential backoff on network
+    /// errors and 5xx responses.
+    fn request(&mut self, method: &str, url: &str, headers: &[String], sink: &mut dyn Sink)
+        -> Result<u32>
+    {
+        let mut backoff = self.backoff;
+        let mut attempt = 0;
+        loop {
+            if attempt > 0 {
+                sink.restart()?;
+            }
+            attempt += 1;
+            let start = Instant::now();
+            let result = self.perform(method, url, headers, sink);
+            let elapsed = start.elapsed();
+            let retry = match result {
+                Ok(code) => {
+                    println!("{} {}: {} in {:.2?}", method, url, code, elapsed);
+                    if code < 500 {
+                        return Ok(code)
+                    }
+                    format!("server error {}", code)
+                }
+                Err(e) => {
+                    println!("{} {}: failed after {:.2?}: {}", method, url, elapsed, e);
+                    e.to_string()
+                }
+            };
+            if attempt > self.retries {
+                return Err(format!("{} {} failed after {} attempts: {}",
+                                   method, url, attempt, retry).into())
+            }
+            println!("retrying in {:?}", backoff);
+            thread::sleep(backoff);
+            backoff *= 2;
+        }
+    }
+
+    fn perform(&mut self, method: &str, url: &str, headers: &[String], sink: &mut dyn Sink)
+        -> Result<u32>
+    {
+        self.handle.reset();
+        self.handle.url(url)?;
+        self.handle.follow_location(true)?;
+        match method {
+            "HEAD" => self.handle.nobody(true)?,
+            "POST" => {
+                self.handle.post(true)?;
+                self.handle.post_field_size(0)?;
+            }
+            _ => self.handle.get(true)?,
+        }
+        if !headers.is_empty() {
+            let mut list = List::new();
+            for header in headers {
+                list.append(header)?;
+            }
+            self.handle.http_headers(list)?;
+        }
+        self.handle.connect_timeout(self.connect_timeout)?;
+        // There's no plain read timeout in libcurl, so give up on transfers
+        // that haven't received a single byte for that long instead.
+        self.handle.low_speed_limit(1)?;
+        self.handle.low_speed_time(self.read_timeout)?;
+        if let Some(speed) = self.max_recv_speed {
+            self.handle.max_recv_speed(speed)?;
+        }
+        if let Some(proxy) = proxy_for(url) {
+            self.handle.proxy(&proxy)?;
+        }
+        if let Some(no_proxy) = env_var("no_proxy") {
+            self.handle.noproxy(&no_proxy)?;
+        }
+
+        let mut write_err = None;
+        let result = {
+            let mut t = self.handle.transfer();
+            t.write_function(|data| {
+                match sink.write_all(data) {
+                    Ok(()) => Ok(data.len()),
+                    Err(e) => {
+                        write_err = Some(e);
+                        Ok(0)
+                    }
+                }
+            })?;
+            t.perform()
+        };
+        if let Some(e) = write_err {
+            return Err(e.into())
+        }
+        if url.starts_with("file://") {
+            // Local files have no status codes, so make up the ones callers
+            // look for.
+            return match result {
+                Ok(()) => Ok(200),
+                Err(ref e) if e.is_file_couldnt_read_file() => Ok(404),
+                Err(e) => Err(e.into()),
+            }
+        }
+        result?;
+        Ok(self.handle.response_code()?)
+    }
+}
+
+/// Where the body of a response goes.
+trait Sink: Write {
+    /// Discards everything written by a failed attempt before a retry.
+    fn restart(&mut self) -> io::Result<()>;
+}
+
+impl Sink for Vec<u8> {
+    fn restart(&mut self) -> io::Result<()> {
+        self.clear();
+        Ok(())
+    }
+}
+
+impl Sink for File {
+    fn restart(&mut self) -> io::Result<()> {
+        self.set_len(0)?;
+        self.seek(SeekFrom::Start(0))?;
+        Ok(())
+    }
+}
+
+impl Sink for io::Sink {
+    fn restart(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A sink adding everything written to it to a shared counter, and taking it
+/// back out again on a retry.
+struct Counting<'a, S> {
+    inner: S,
+    received: &'a AtomicU64,
+    written: u64,
+}
+
+impl<'a, S: Write> Write for Counting<'a, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.received.fetch_add(n as u64, Ordering::Relaxed);
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<'a, S: Sink> Sink for Counting<'a, S> {
+    fn restart(&mut self) -> io::Result<()> {
+        self.received.fetch_sub(self.written, Ordering::Relaxed);
+        self.written = 0;
+        self.inner.restart()
+    }
+}
+
+/// Picks the proxy for `url` from the usual environment variables.
+///
+/// libcurl reads most of these itself, but deliberately ignores
+/// `HTTP_PROXY` in uppercase, so they're all looked up here instead.
+fn proxy_for(url: &str) -> Option<String> {
+    let scheme_var = if url.starts_with("https://") {
+        "https_proxy"
+    } else {
+        "http_proxy"
+    };
+    env_var(scheme_var).or_else(|| env_var("all_proxy"))
+}
+
+/// Reads an environment variable, in lowercase first and then in uppercase,
+/// ignoring it if it's empty.
+fn env_var(name: &str) -> Option<String> {
+    env::var(name).ok()
+        .or_else(|| env::var(name.to_uppercase()).ok())
+        .filter(|v| !v.is_empty())
+}
+
+/// Percent-encodes everything in the path `s` but `/` and the characters S3
+/// leaves alone in keys.
+pub fn encode_path(s: &str) -> String {
+    let mut encoded = String::new();
+    for &b in s.as_bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                encoded.push(b as char);
+            }
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use super::*;
+    use test_server::{Reply, Server};
+
+    fn client(config: &str) -> Client {
+        let mut client = Client::new(&config.parse().unwrap());
+        client.backoff = Duration::from_millis(10);
+        client
+    }
+
+    fn ok(body: &str) -> Reply {
+        Reply::Status(200, body.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn retries_server_errors() {
+        let server = Server::sequence(vec![
+            Reply::Status(500, b"oops".to_vec()),
+            Reply::Status(503, Vec::new()),
+            ok("body"),
+        ]);
+        let body = client("").get_ok(&format!("{}/a", server.url)).unwrap();
+        assert_eq!(body, b"body");
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[test]
+    fn retries_reset_connections() {
+        let server = Server::sequence(vec![Reply::Reset, Reply::Reset, ok("body")]);
+        let body = client("").get_ok(&format!("{}/a", server.url)).unwrap();
+        assert_eq!(body, b"body");
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[test]
+    fn returns_client_errors_without_retrying() {
+        let server = Server::sequence(vec![Reply::Status(404, Vec::new()), ok("body")]);
+        let mut client = client("");
+        assert_eq!(client.head(&format!("{}/a", server.url)).unwrap(), 404);
+        assert_eq!(server.requests().len(), 1);
+        assert!(client.get_ok(&format!("{}/a", server.url)).is_ok());
+    }
+
+    #[test]
+    fn gives_up_after_the_retry_limit() {
+        let server = Server::sequence(vec![Reply::Status(502, Vec::new())]);
+        let err = client("http-retries = 2").get(&format!("{}/a", server.url)).err().unwrap();
+        assert!(err.to_string().contains("failed after 3 attempts: server error 502"), "{}", err);
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[test]
+    fn times_out_stalled_responses() {
+        let server = Server::sequence(vec![Reply::Hang(Duration::from_secs(10)), ok("body")]);
+        let start = Instant::now();
+        let err = client("http-read-timeout = 1\nhttp-retries = 0")
+            .get(&format!("{}/a", server.url))
+            .err().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5), "took {:?}", start.elapsed());
+        assert!(err.to_string().contains("failed after 1 attempts"), "{}", err);
+
+        let body = client("http-read-timeout = 1\nhttp-retries = 1")
+            .get_ok(&format!("{}/a", server.url))
+            .unwrap();
+        assert_eq!(body, b"body");
+    }
+
+    #[test]
+    fn downloads_start_over_on_a_retry() {
+        let server = Server::sequence(vec![Reply::Status(500, b"partial".to_vec()), ok("body")]);
+        let dst = env::temp_dir().join(format!("http-download-{}", process::id()));
+        let received = AtomicU64::new(0);
+        client("").download_counted(&format!("{}/a", server.url), &dst, &received).unwrap();
+        assert_eq!(fs::read(&dst).unwrap(), b"body");
+        assert_eq!(received.load(Ordering::Relaxed), 4);
+        fs::remove_file(&dst).unwrap();
+    }
+
+    #[test]
+    fn treats_missing_local_files_as_not_found() {
+        let path = env::temp_dir().join(format!("http-missing-{}", process::id()));
+        let response = client("").get(&format!("file://{}", path.display())).unwrap();
+        assert_eq!(response.code, 404);
+    }
+
+    #[test]
+    fn encodes_paths() {
+        assert_eq!(encode_path("rustc-builds/abc/rust-1.0.tar.xz"),
+                   "rustc-builds/abc/rust-1.0.tar.xz");
+        assert_eq!(encode_path("a b+c~d/é"), "a%20b%2Bc~d/%C3%A9");
+    }
+}
@@ -1,21 +1,33 @@
 extern crate curl;
 extern crate flate2;
 extern crate fs2;
+extern crate git2;
+extern crate pulldown_cmark;
+extern crate hex;
+extern crate md5;
 extern crate rand;
 #[macro_use]
 extern crate serde_json;
+extern crate sha2;
 extern crate tar;
 extern crate toml;
 extern crate xz2;
 
+use std::collections::{BTreeMap, BTreeSet};
 use std::env;
+use std::error::Error;
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, Read, Write};
 use std::path::{PathBuf, Path};
 use std::process::Command;
+use std::time::{Duration, Instant};
 
-use curl::easy::Easy;
 use fs2::FileExt;
+use git2::{Oid, Repository};
+
+use cdn::Cdn;
+use mirror::Mirror;
+use storage::Storage;
 
 macro_rules! t {
     ($e:expr) => (match $e {
@@ -24,49 +36,235 @@ macro_rules! t {
     })
 }
 
+mod cache;
+mod cdn;
+mod changelog;
+mod docs;
+mod download;
+mod git;
+mod gpg;
+mod hashes;
+mod http;
+mod metadata;
+mod mirror;
+mod prune;
+mod release_notes;
+mod smoke;
+mod storage;
+#[cfg(test)]
+mod test_server;
+mod verify;
+mod versions;
+
 struct Context {
     work: PathBuf,
     release: String,
-    handle: Easy,
+    channel: String,
+    bootstrap: bool,
+    local_build: Option<PathBuf>,
+    apply: bool,
+    http: http::Client,
 	secrets: toml::Value,
     date: String,
     current_version: Option<String>,
+    report: serde_json::Map<String, serde_json::Value>,
+    invalidations: Vec<String>,
 }
 
+/// How deep the history of a branch is fetched, one step at a time, to find
+/// the previous release in for the changelog. Releases of the same channel are
+/// at most a few hundred commits apart, and each step fetches the trees of
+/// every commit in it, so this stops well before the whole history.
+const CHANGELOG_DEPTHS: &[i32] = &[50, 500];
+
+/// Bytes in a GiB, for reporting sizes.
+const GIB: f64 = (1 << 30) as f64;
+
+/// How many times the live manifest and its `.sha256` and `.asc` are fetched,
+/// a minute apart, before believing they don't match. Right after a release
+/// the CDN can still serve some of them from the previous one.
+const LIVE_MANIFEST_ATTEMPTS: u32 = 5;
+
 // Called as:
 //
-//  $prog work/dir release-channel path/to/secrets.toml
+//  $prog work/dir release-channel path/to/secrets.toml [--bootstrap]
+//        [--local-build path/to/rust]
+//  $prog work/dir prune path/to/secrets.toml [--apply]
+//
+// `--bootstrap` publishes the first release of a channel, which doesn't have a
+// live manifest to compare against yet.
+//
+// `--local-build` publishes the artifacts a local `x.py dist` left in
+// `build/dist` of the given rust checkout, instead of fetching the branch and
+// downloading what CI built for it.
+//
+// `prune` lists the dated release archives past the retention configured in
+// `[dist.retention]`, and deletes them with `--apply`.
 fn main() {
+    let mut args = Vec::new();
+    let mut bootstrap = false;
+    let mut local_build = None;
+    let mut apply = false;
+    let mut all_args = env::args_os();
+    while let Some(arg) = all_args.next() {
+        if arg == "--bootstrap" {
+            bootstrap = true;
+        } else if arg == "--local-build" {
+            let path = all_args.next().expect("--local-build needs a path");
+            local_build = Some(t!(env::current_dir()).join(path));
+        } else if arg == "--apply" {
+            apply = true;
+        } else {
+            args.push(arg);
+        }
+    }
     let mut secrets = String::new();
-    t!(t!(File::open(env::args().nth(3).unwrap())).read_to_string(&mut secrets));
+    t!(t!(File::open(&args[3])).read_to_string(&mut secrets));
+    let secrets: toml::Value = t!(secrets.parse());
 
     Context {
-        work: t!(env::current_dir()).join(env::args_os().nth(1).unwrap()),
-        release: env::args().nth(2).unwrap(),
-        secrets: t!(secrets.parse()),
-        handle: Easy::new(),
+        work: t!(env::current_dir()).join(&args[1]),
+        release: args[2].clone().into_string().unwrap(),
+        channel: String::new(),
+        bootstrap,
+        local_build,
+        apply,
+        http: http::Client::new(&secrets["dist"]),
+        secrets,
         date: output(Command::new("date").arg("+%Y-%m-%d")).trim().to_string(),
         current_version: None,
+        report: serde_json::Map::new(),
+        invalidations: Vec::new(),
     }.run()
 }
 
 impl Context {
     fn run(&mut self) {
         let _lock = self.lock();
-        self.update_repo();
+        self.clean_work_dir();
+        if self.release == "prune" {
+            return self.prune()
+        }
 
-        let override_var = env::var("PROMOTE_RELEASE_OVERRIDE_BRANCH");
-        let branch = if let Ok(branch) = override_var.as_ref() {
-            branch
-        } else {
-            match &self.release[..] {
-                "nightly" => "master",
-                "beta" => "beta",
-                "stable" => "stable",
-                _ => panic!("unknown release: {}", self.release),
+        let (branch, channel, upload_dir) = channel_config(&self.secrets["dist"], &self.release);
+        let branch = env::var("PROMOTE_RELEASE_OVERRIDE_BRANCH").unwrap_or(branch);
+        self.channel = channel;
+        if let Some(dir) = upload_dir {
+            self.secrets["dist"].as_table_mut().unwrap()
+                .insert("upload-dir".to_string(), toml::Value::String(dir));
+        }
+        if self.local_build.is_none() {
+            self.update_repo(&branch);
+        }
+        self.do_release(&branch);
+    }
+
+    /// Deletes what the `[dist.retention]` rules say has expired from the
+    /// dated release archives.
+    ///
+    /// Which channels were released on which date, and what each of those
+    /// releases consists of, is learned from the manifests in the dated
+    /// directories. Anything a live manifest or a kept dated manifest
+    /// references is never deleted, and neither are directories without any
+    /// manifests.
+    ///
+    /// What would be deleted is always listed first, and is only actually
+    /// deleted with `--apply`, in which case each deletion is appended to the
+    /// audit log at `dist.prune-audit-log` (`prune-audit.log` in the work
+    /// directory by default).
+    fn prune(&mut self) {
+        let storage = self.storage();
+        let upload_dir = self.secrets["dist"]["upload-dir"].as_str().unwrap().to_string();
+        let retention = prune::Retention::from_config(self.secrets["dist"].get("retention"));
+        let scratch = self.work.join("prune");
+        t!(fs::create_dir_all(&scratch));
+
+        let mut dirs = BTreeMap::new();
+        let mut releases = Vec::new();
+        for date in t!(storage.list_dirs(&format!("{}/", upload_dir))) {
+            if !prune::is_date(&date) {
+                continue
+            }
+            let prefix = format!("{}/{}/", upload_dir, date);
+            let objects = t!(storage.list(&prefix));
+            for object in objects.iter() {
+                if let Some(channel) = prune::manifest_channel(&object.key[prefix.len()..]) {
+                    releases.push(prune::Release {
+                        channel,
+                        date: date.clone(),
+                        keys: self.manifest_keys(&*storage, &object.key, &scratch),
+                    });
+                }
             }
+            dirs.insert(date, objects);
+        }
+
+        let mut protected = BTreeSet::new();
+        let prefix = format!("{}/", upload_dir);
+        for object in t!(storage.list(&prefix)) {
+            if prune::manifest_channel(&object.key[prefix.len()..]).is_some() {
+                protected.extend(self.manifest_keys(&*storage, &object.key, &scratch));
+            }
+        }
+
+        let doomed = retention.expired(&dirs, &releases, &protected, &self.date);
+        for object in doomed.iter() {
+            println!("expired: {} ({} bytes)", object.key, object.size);
+        }
+        println!("{} objects taking {:.1} GiB are past retention",
+                 doomed.len(), doomed.iter().map(|o| o.size).sum::<u64>() as f64 / GIB);
+        if !self.apply {
+            return println!("dry run, pass --apply to delete them")
+        }
+
+        let audit_log = match self.secrets["dist"].get("prune-audit-log") {
+            Some(path) => PathBuf::from(path.as_str().expect("prune-audit-log not a string")),
+            None => self.work.join("prune-audit.log"),
         };
-        self.do_release(branch);
+        let now = output(Command::new("date").arg("-u").arg("+%Y-%m-%dT%H:%M:%SZ"));
+        for batch in doomed.chunks(1000) {
+            let keys = batch.iter().map(|o| o.key.clone()).collect::<Vec<_>>();
+            t!(storage.delete(&keys));
+            let mut log = t!(OpenOptions::new().create(true).append(true).open(&audit_log));
+            for object in batch {
+                let entry = json!({
+                    "deleted-at": now.trim(),
+                    "key": object.key,
+                    "size": object.size,
+                    "etag": object.etag,
+                });
+                t!(writeln!(log, "{}", entry));
+            }
+        }
+        println!("deleted {} objects, recorded in {}", doomed.len(), audit_log.display());
+
+        let paths = doomed.iter().map(|o| format!("/{}", o.key)).collect();
+        self.invalidate("static", paths, &format!("/{}/*", upload_dir));
+    }
+
+    /// Keys of everything the manifest at `key` references, including the
+    /// manifest itself.
+    fn manifest_keys(&self, storage: &dyn Storage, key: &str, scratch: &Path)
+        -> BTreeSet<String>
+    {
+        let dst = scratch.join("manifest.toml");
+        if !t!(storage.download_file(key, &dst)) {
+            panic!("failed to download {}", key);
+        }
+        let manifest: toml::Value = t!(t!(fs::read_to_string(&dst)).parse());
+        let addr = self.secrets["dist"]["upload-addr"].as_str().unwrap();
+        let upload_dir = self.secrets["dist"]["upload-dir"].as_str().unwrap();
+        // Whatever can't be mapped to a key can't be protected either, so
+        // rather than risk deleting it nothing is deleted at all.
+        let mut keys = verify::manifest_urls(&manifest).into_iter().map(|url| {
+            match prune::url_key(&url, addr, upload_dir) {
+                Some(key) => key,
+                None => panic!("{} references {}, which isn't under {}/{}",
+                               key, url, addr, upload_dir),
+            }
+        }).collect::<BTreeSet<_>>();
+        keys.insert(key.to_string());
+        keys
     }
 
     /// Locks execution of concurrent invocations of this script in case one
@@ -83,48 +281,73 @@ impl Context {
         file
     }
 
-    /// Update the rust repository we have cached, either cloning a fresh one or
-    /// fetching remote references
-    fn update_repo(&mut self) {
-        // Clone/update the repo
-        let dir = self.rust_dir();
-        if dir.is_dir() {
-            println!("fetching");
-            run(Command::new("git")
-                        .arg("fetch")
-                        .arg("origin")
-                        .current_dir(&dir));
-        } else {
-            println!("cloning");
-            run(Command::new("git")
-                        .arg("clone")
-                        .arg("https://github.com/rust-lang/rust")
-                        .arg(&dir));
+    /// Removes the scratch directories of a previous run, which are left
+    /// behind when it crashed halfway through. The rust checkout is kept, as
+    /// it's updated incrementally.
+    fn clean_work_dir(&self) {
+        let dirs = ["dl", "build", "docs", "upload", "versions-index", "smoke",
+                    "live-manifest", "prune"];
+        for dir in dirs.iter() {
+            let path = self.work.join(dir);
+            if path.exists() {
+                println!("removing {}", path.display());
+                t!(fs::remove_dir_all(&path));
+            }
         }
     }
 
+    /// Update the rust repository we have cached, creating it if it doesn't
+    /// exist yet, and fetch the latest state of `branch` from the remote.
+    fn update_repo(&mut self, branch: &str) {
+        let dir = self.rust_dir();
+        let repo = t!(git::open_or_init(&dir, self.upstream_repo()));
+        println!("fetching {}", branch);
+        t!(git::fetch_branches(&repo, &[branch], 1));
+    }
+
     /// Does a release for the `branch` specified.
     fn do_release(&mut self, branch: &str) {
         // Learn the precise rev of the remote branch, this'll guide what we
-        // download.
-        let rev = output(Command::new("git")
-                                 .arg("rev-parse")
-                                 .arg(format!("origin/{}", branch))
-                                 .current_dir(&self.rust_dir()));
-        let rev = rev.trim();
+        // download. Local builds are of whatever is checked out.
+        let rev = match self.local_build {
+            Some(ref checkout) => local_rev(checkout),
+            None => t!(git::remote_branch_rev(&self.repo(), branch)).to_string(),
+        };
+        let rev = &rev[..];
         println!("{} rev is {}", self.release, rev);
+        let mut mirrors = self.mirrors();
 
         // Download the current live manifest for the channel we're releasing.
-        // Through that we learn the current version of the release.
-        let manifest = self.download_manifest();
-        let previous_version = manifest["pkg"]["rust"]["version"]
-                                       .as_str()
-                                       .expect("rust version not a string");
-        println!("previous version: {}", previous_version);
+        // Through that we learn the current version of the release. There's
+        // none for the first release of a channel, which has to be asked for
+        // explicitly with `--bootstrap`.
+        let previous_version = match self.download_manifest() {
+            Some(_) if self.bootstrap => {
+                panic!("--bootstrap given but {} already has a live manifest",
+                       self.release)
+            }
+            Some(manifest) => {
+                let version = manifest["pkg"]["rust"]["version"]
+                    .as_str()
+                    .expect("rust version not a string")
+                    .to_string();
+                println!("previous version: {}", version);
+                Some(version)
+            }
+            None if self.bootstrap => {
+                println!("no live manifest, bootstrapping {}", self.release);
+                None
+            }
+            None => {
+                panic!("no live manifest at {}, pass --bootstrap to publish the \
+                        first release of a channel", self.manifest_url())
+            }
+        };
+        let previous_version = previous_version.as_ref().map(|v| &v[..]);
 
         // If the previously released version is the same rev, then there's
         // nothing for us to do, nothing has changed.
-        if previous_version.contains(&rev[..7]) {
+        if previous_version.is_some_and(|v| v.contains(&rev[..7])) {
             return println!("found rev in previous version, skipping");
         }
 
@@ -148,35 +371,76 @@ impl Context {
         // different and the versions are the same then there's nothing for us
         // to do. This represents a scenario where changes have been merged to
         // the stable/beta branch but the version bump hasn't happened yet.
-        self.download_artifacts(&rev);
-        if self.current_version_same(&previous_version) {
-            return println!("version hasn't changed, skipping");
+        if self.local_build.is_some() {
+            self.copy_local_artifacts();
+        } else {
+            self.check_disk_space(rev);
+            self.download_artifacts(&rev);
+        }
+        self.prepare_artifacts();
+        // The version is needed for the docs and release notes even when
+        // there's no previous release to compare it with.
+        let current = self.read_current_version();
+        if let Some(previous_version) = previous_version {
+            if self.current_version_same(previous_version, &current) {
+                return println!("version hasn't changed, skipping");
+            }
         }
 
         self.assert_all_components_present();
+        self.prepare_docs();
 
         // Ok we've now determined that a release needs to be done. Let's
         // configure rust, build a manifest and sign the artifacts we just downloaded, and upload the
         // signatures and manifest to the CI bucket.
         self.configure_rust(rev);
         self.sign_artifacts();
-        self.upload_signatures(&rev);
+        if self.local_build.is_none() {
+            self.upload_signatures(&rev);
+        }
 
         // Merge all the signatures with the download files, and then sync that
         // whole dir up to the release archives
         for file in t!(self.build_dir().join("build/dist/").read_dir()) {
             let file = t!(file);
-            t!(fs::copy(file.path(), self.dl_dir().join(file.file_name())));
+            // Files in the download dir may be hard links into the artifact
+            // cache, so replace rather than overwrite them.
+            let dst = self.dl_dir().join(file.file_name());
+            drop(fs::remove_file(&dst));
+            t!(fs::copy(file.path(), dst));
+        }
+        if self.channel == "stable" {
+            self.write_release_notes(rev);
         }
+        // The history needed for the changelog is fetched from upstream,
+        // which local builds don't touch.
+        if let (Some(previous_version), None) = (previous_version, &self.local_build) {
+            self.write_changelog(branch, rev, previous_version);
+        }
+        self.write_report(rev, previous_version);
+        self.write_sha256sums();
         self.publish_archive();
-        self.publish_docs();
+        self.publish_docs(rev);
         self.publish_release();
 
-        self.invalidate_cloudfront();
+        // The release is live from here on, so problems found checking it
+        // don't stop it from going out to the mirrors too. They fail the run
+        // once that's done instead.
+        let mut problems = Vec::new();
+        if let Err(e) = self.invalidate_static() {
+            problems.push(format!("failed to invalidate the static CDN: {}", e));
+        }
+        problems.extend(self.verify_published());
+        problems.extend(self.smoke_test());
+        self.publish_mirrors(&mut mirrors);
 
         // Clean up after ourselves to avoid leaving gigabytes of artifacts
         // around.
-        drop(fs::remove_dir_all(&self.dl_dir()));
+        self.clean_work_dir();
+        if !problems.is_empty() {
+            panic!("verification of the published release failed:\n{}",
+                   problems.join("\n"));
+        }
     }
 
     fn configure_rust(&mut self, rev: &str) {
@@ -185,15 +449,21 @@ impl Context {
         t!(fs::create_dir_all(&build));
         let rust = self.rust_dir();
 
-        run(Command::new("git")
-                    .arg("reset")
-                    .arg("--hard")
-                    .arg(rev)
-                    .current_dir(&rust));
+        // A local build is run from its own checkout, which is left alone.
+        // Otherwise we check out the submodules we need ourselves, and
+        // rustbuild mustn't try to update the rest of them.
+        let mut configure = Command::new(rust.join("configure"));
+        if self.local_build.is_none() {
+            let paths = self.sparse_paths();
+            println!("checking out {} paths of {}", paths.len(), rev);
+            let repo = self.repo();
+            t!(git::sparse_checkout(&repo, rev, &paths));
+            t!(git::update_submodules(&repo));
+            configure.arg("--set").arg("build.submodules=false");
+        }
 
-        run(Command::new(rust.join("configure"))
-                    .current_dir(&build)
-                    .arg(format!("--release-channel={}", self.release)));
+        run(configure.current_dir(&build)
+                     .arg(format!("--release-channel={}", self.channel)));
         let mut config = String::new();
         let path = build.join("config.toml");
         drop(File::open(&path).and_then(|mut f| f.read_to_string(&mut config)));
@@ -216,13 +486,145 @@ upload-addr = \"{}/{}\"
         t!(t!(File::create(&path)).write_all(new_config.as_bytes()));
     }
 
-    fn current_version_same(&mut self, prev: &str) -> bool {
-        // nightly's always changing
-        if self.release == "nightly" {
-            return false
+    /// `RELEASES.md` as of `rev`. A local build's is read from its checkout
+    /// instead, which needn't be a git repository.
+    fn releases_md(&self, rev: &str) -> Result<String, Box<dyn Error>> {
+        let releases = match self.local_build {
+            Some(ref checkout) => fs::read(checkout.join("RELEASES.md"))?,
+            None => git::read_file(&self.repo(), rev, "RELEASES.md")?,
+        };
+        Ok(String::from_utf8_lossy(&releases).into_owned())
+    }
+
+    /// Pulls the section for the version being released out of `RELEASES.md`
+    /// at `rev`, and puts it next to the artifacts as both Markdown and HTML.
+    fn write_release_notes(&mut self, rev: &str) {
+        let version = self.current_version.clone().unwrap();
+        let releases = t!(self.releases_md(rev));
+        let notes = match release_notes::extract(&releases, &version) {
+            Some(notes) => notes,
+            None => return println!("no release notes for {} in RELEASES.md", version),
+        };
+        let base = self.dl_dir().join(format!("rust-{}-release-notes", version));
+        t!(t!(File::create(base.with_extension("md"))).write_all(notes.as_bytes()));
+        let html = release_notes::to_html(&version, &notes);
+        t!(t!(File::create(base.with_extension("html"))).write_all(html.as_bytes()));
+        self.report.insert("release-notes".to_string(), json!(notes));
+    }
+
+    /// Writes the list of pull requests merged since the previous release as
+    /// `changelog-rust-$channel.{json,md}` next to the artifacts.
+    ///
+    /// The previous release's commit is learned from the short hash in its
+    /// version string, and the history of the branch is deepened until it's
+    /// reached, up to `CHANGELOG_DEPTHS`. Nothing is written if it can't be
+    /// found within that, for example because the branch was force-pushed
+    /// since, or if fetching fails.
+    fn write_changelog(&mut self, branch: &str, rev: &str, previous_version: &str) {
+        let previous_rev = previous_version.split('(')
+            .nth(1)
+            .and_then(|s| s.split(' ').next());
+        let previous_rev = match previous_rev {
+            Some(rev) => rev,
+            None => return println!("no commit in previous version, skipping changelog"),
+        };
+        let repo = self.repo();
+        let from = match git::deepen_until(&repo, branch, previous_rev, CHANGELOG_DEPTHS) {
+            Ok(Some(from)) => from,
+            Ok(None) => return println!("{} not found in the last {} commits of {}, \
+                                         skipping changelog",
+                                        previous_rev, CHANGELOG_DEPTHS.last().unwrap(), branch),
+            Err(e) => return println!("failed to fetch history of {}, skipping changelog: {}",
+                                      branch, e),
+        };
+        let entries = t!(changelog::collect(&repo, from, t!(Oid::from_str(rev))));
+        println!("{} pull requests merged since {}", entries.len(), previous_rev);
+
+        let base = self.dl_dir().join(format!("changelog-rust-{}", self.channel));
+        let json = changelog::to_json(&entries);
+        t!(t!(File::create(base.with_extension("json"))).write_all(json.as_bytes()));
+        let url = self.upstream_repo().trim_end_matches(".git");
+        let md = changelog::to_markdown(&entries, previous_rev, &rev[..9], url);
+        t!(t!(File::create(base.with_extension("md"))).write_all(md.as_bytes()));
+        self.report.insert("merged-pull-requests".to_string(), json!(entries.len()));
+    }
+
+    /// Writes out a summary of this release as `release-report-$release.json`
+    /// next to the artifacts, so it's published along with them.
+    fn write_report(&mut self, rev: &str, previous_version: Option<&str>) {
+        let mut report = json!({
+            "release": self.release,
+            "channel": self.channel,
+            "date": self.date,
+            "rev": rev,
+            "previous-version": previous_version,
+            "version": self.current_version,
+            "bootstrapped": self.bootstrap,
+            "local-build": self.local_build.is_some(),
+        });
+        for (key, value) in self.report.iter() {
+            report[key] = value.clone();
         }
-        let prev_version = prev.split(' ').next().unwrap();
+        let dst = self.dl_dir().join(format!("release-report-{}.json", self.release));
+        t!(t!(File::create(&dst)).write_all(report.to_string().as_bytes()));
+    }
 
+    /// Writes `SHA256SUMS` covering every file next to the artifacts, along
+    /// with a detached signature of it in `SHA256SUMS.asc`, so mirrors can
+    /// verify a whole release with two files.
+    fn write_sha256sums(&mut self) {
+        let dl = self.dl_dir();
+        let sums = t!(hashes::sha256sums(&dl, |name| {
+            !name.ends_with(".sha256") && !name.starts_with("SHA256SUMS")
+        }));
+        let path = dl.join("SHA256SUMS");
+        t!(t!(File::create(&path)).write_all(sums.as_bytes()));
+        println!("wrote hashes of {} files to {}", sums.lines().count(), path.display());
+
+        let password_file = self.secrets["dist"]["gpg-password-file"].as_str().unwrap();
+        run(&mut gpg::detach_sign(&path, &dl.join("SHA256SUMS.asc"), Path::new(password_file)));
+    }
+
+    /// Pathspecs of the files in rust-lang/rust that we check out.
+    ///
+    /// All we do with the checkout is run `./configure` and build
+    /// `src/tools/build-manifest` through `x.py`, so we only need rustbuild
+    /// itself plus build-manifest. Cargo loads the whole workspace when
+    /// building a tool though, so every member's manifest and crate root has
+    /// to be present too, and the members that are submodules are checked out
+    /// whole along with `.gitmodules`. The defaults can be overridden with
+    /// `dist.sparse-checkout-paths` in the secrets.
+    fn sparse_paths(&self) -> Vec<String> {
+        if let Some(paths) = self.secrets["dist"].get("sparse-checkout-paths") {
+            return paths.as_array()
+                .expect("sparse-checkout-paths not an array")
+                .iter()
+                .map(|p| p.as_str().expect("sparse path not a string").to_string())
+                .collect()
+        }
+        [
+            "x.py",
+            "configure",
+            ".gitmodules",
+            "Cargo.toml",
+            "Cargo.lock",
+            "config.toml.example",
+            "src/stage0.txt",
+            "src/bootstrap",
+            "src/tools/build-manifest",
+            "src/tools/cargo",
+            "src/tools/miri",
+            "src/tools/rls",
+            "src/tools/rust-installer",
+            "*Cargo.toml",
+            "*src/lib.rs",
+            "*src/main.rs",
+        ].iter().map(|s| s.to_string()).collect()
+    }
+
+    /// Reads the version of the release out of the rustc tarball, recording
+    /// it in `current_version` and returning the whole version line.
+    fn read_current_version(&mut self) -> String {
         let current = t!(self.dl_dir().read_dir()).filter_map(|e| {
             let e = t!(e);
             let filename = e.file_name().into_string().unwrap();
@@ -255,6 +657,16 @@ upload-addr = \"{}/{}\"
 
         let current_version = current.split(' ').next().unwrap();
         self.current_version = Some(current_version.to_string());
+        current
+    }
+
+    fn current_version_same(&self, prev: &str, current: &str) -> bool {
+        // nightly's always changing
+        if self.channel == "nightly" {
+            return false
+        }
+        let prev_version = prev.split(' ').next().unwrap();
+        let current_version = current.split(' ').next().unwrap();
 
         // The release process for beta looks like so:
         //
@@ -280,7 +692,7 @@ upload-addr = \"{}/{}\"
     /// Note that we already don't merge PRs in rust-lang/rust that don't
     /// build cargo, so this cannot realistically fail.
     fn assert_all_components_present(&self) {
-        if self.release != "nightly" {
+        if self.channel != "nightly" {
             return
         }
         let components = t!(self.dl_dir().read_dir())
@@ -298,19 +710,110 @@ upload-addr = \"{}/{}\"
         // assert!(components.iter().any(|s| s.starts_with("clippy-")));
     }
 
+    /// Refuses to go any further unless the disk the work directory is on
+    /// has room for a release of `rev`.
+    ///
+    /// That's estimated as the size of the artifacts in the source bucket
+    /// times `dist.disk-space-factor` (3 by default), which leaves room for
+    /// the recompressed tarballs, the unpacked docs and their bundle.
+    fn check_disk_space(&self, rev: &str) {
+        let (bucket, prefix) = self.source_location(rev);
+        let artifacts = self.list_objects(&bucket, &prefix)
+            .values()
+            .map(|o| o.size)
+            .sum::<u64>();
+        let factor = self.secrets["dist"].get("disk-space-factor")
+            .map(|f| f.as_integer().expect("disk-space-factor not an integer") as u64)
+            .unwrap_or(3);
+        let needed = artifacts * factor;
+        let available = t!(fs2::available_space(&self.work));
+        println!("artifacts take {:.1} GiB, need {:.1} GiB, {:.1} GiB available",
+                 artifacts as f64 / GIB, needed as f64 / GIB, available as f64 / GIB);
+        if needed > available {
+            panic!("not enough disk space in {} for a release of {}: need {:.1} GiB \
+                    but only {:.1} GiB is available",
+                   self.work.display(), rev, needed as f64 / GIB, available as f64 / GIB);
+        }
+    }
+
     fn download_artifacts(&mut self, rev: &str) {
         let dl = self.dl_dir();
         drop(fs::remove_dir_all(&dl));
         t!(fs::create_dir_all(&dl));
 
-        let src = format!("s3://rust-lang-ci2/rustc-builds/{}/", rev);
-        run(self.aws_s3()
-                .arg("cp")
-                .arg("--recursive")
-                .arg("--only-show-errors")
-                .arg(&src)
-                .arg(format!("{}/", dl.display())));
+        // Artifacts we already have from an earlier run are taken from the
+        // cache, and only the rest is downloaded.
+        let (bucket, prefix) = self.source_location(rev);
+        let objects = self.list_objects(&bucket, &prefix);
+        let cache = t!(cache::Cache::new(&self.work.join("cache"), self.artifact_cache_size()));
+        let region = match self.secrets["dist"].get("source-url") {
+            Some(_) => String::new(),
+            None => self.source_region(&bucket),
+        };
+        let mut missing = Vec::new();
+        let mut etags = BTreeMap::new();
+        for object in objects.values() {
+            let dst = dl.join(&object.key[prefix.len()..]);
+            if !t!(cache.get(&object.etag, &dst)) {
+                etags.insert(dst.clone(), object.etag.clone());
+                missing.push(download::Download {
+                    url: self.source_http_url(&bucket, &object.key, &region),
+                    dst,
+                    size: object.size,
+                });
+            }
+        }
+        println!("{} of {} artifacts cached, downloading the rest",
+                 objects.len() - missing.len(), objects.len());
+
+        // Downloads are checked against the `.sha256` CI uploads next to
+        // them, so those come first.
+        let (sums, rest) = missing.into_iter()
+            .partition::<Vec<_>, _>(|d| d.dst.extension().is_some_and(|e| e == "sha256"));
+        let options = self.download_options();
+        let mut failed = Vec::new();
+        for batch in [sums, rest] {
+            failed.extend(download::all(batch, &self.secrets["dist"], &options, |d| {
+                let mut sidecar = d.dst.clone().into_os_string();
+                sidecar.push(".sha256");
+                let sha256 = fs::read_to_string(&sidecar).ok();
+                let sha256 = sha256.as_ref().and_then(|s| s.split_whitespace().next());
+                Ok(cache.insert(&etags[&d.dst], &d.dst, d.size, sha256)?)
+            }));
+        }
+        t!(cache.evict());
+        if !failed.is_empty() {
+            for (d, e) in &failed {
+                println!("failed to download {}: {}", d.url, e);
+            }
+            panic!("failed to download {} artifacts, rerun to retry just those",
+                   failed.len());
+        }
+    }
 
+    /// Takes the artifacts to release from `build/dist` of the local build
+    /// instead of downloading them.
+    fn copy_local_artifacts(&mut self) {
+        let dl = self.dl_dir();
+        drop(fs::remove_dir_all(&dl));
+        t!(fs::create_dir_all(&dl));
+        let src = self.rust_dir().join("build/dist");
+        println!("taking artifacts from {}", src.display());
+        for file in t!(src.read_dir()) {
+            let file = t!(file);
+            if !t!(file.file_type()).is_file() {
+                continue
+            }
+            let dst = dl.join(file.file_name());
+            if fs::hard_link(file.path(), &dst).is_err() {
+                t!(fs::copy(file.path(), &dst));
+            }
+        }
+    }
+
+    /// Gets the artifacts in `dl_dir` ready for signing.
+    fn prepare_artifacts(&mut self) {
+        let dl = self.dl_dir();
         let mut files = t!(dl.read_dir());
         if files.next().is_none() {
             panic!("appears that this rev doesn't have any artifacts, \
@@ -369,7 +872,7 @@ upload-addr = \"{}/{}\"
     }
 
     fn upload_signatures(&mut self, rev: &str) {
-        let dst = format!("s3://rust-lang-ci2/rustc-builds/{}/", rev);
+        let dst = self.source_url(rev);
         run(self.aws_s3()
                 .arg("cp")
                 .arg("--recursive")
@@ -379,167 +882,629 @@ upload-addr = \"{}/{}\"
     }
 
     fn publish_archive(&mut self) {
-        let bucket = self.secrets["dist"]["upload-bucket"].as_str().unwrap();
         let dir = self.secrets["dist"]["upload-dir"].as_str().unwrap();
-        let dst = format!("s3://{}/{}/{}/", bucket, dir, self.date);
-        run(self.aws_s3()
-                .arg("cp")
-                .arg("--recursive")
-                .arg("--only-show-errors")
-                .arg("--metadata-directive")
-                .arg("REPLACE")
-                .arg("--cache-control")
-                .arg("public")
-                .arg(format!("{}/", self.dl_dir().display()))
-                .arg(&dst));
+        let dst = format!("{}/{}/", dir, self.date);
+        t!(self.storage().upload_dir(&self.dl_dir(), &dst));
+        let paths = self.uploaded_paths(&format!("/{}/{}", dir, self.date));
+        self.invalidations.extend(paths);
     }
 
-    fn publish_docs(&mut self) {
-        let (version, upload_dir) = match &self.release[..] {
-            "stable" => {
-                let vers = &self.current_version.as_ref().unwrap()[..];
-                (vers, "stable")
-            }
-            "beta" => ("beta", "beta"),
-            "nightly" => ("nightly", "nightly"),
+    /// The version docs tarballs are named with, which is the channel name
+    /// for nightly and beta.
+    fn docs_version(&self) -> String {
+        match &self.channel[..] {
+            "stable" => self.current_version.clone().unwrap(),
+            "beta" => "beta".to_string(),
+            "nightly" => "nightly".to_string(),
             _ => panic!(),
-        };
+        }
+    }
+
+    /// Extracts the docs that get published into `work/docs`, and bundles
+    /// the host target's std and rustc docs up as
+    /// `rust-docs-html-$version.tar.xz` next to the artifacts so they get
+    /// signed and published along with everything else.
+    fn prepare_docs(&mut self) {
+        let upload_dir = &self.release[..];
+        let version = &self.docs_version()[..];
 
-        // Pull out HTML documentation from one of the `rust-docs-*` tarballs.
-        // For now we just arbitrarily pick x86_64-unknown-linux-gnu.
+        // Pull out HTML documentation from the `rust-docs-*` tarballs.
         let docs = self.work.join("docs");
         drop(fs::remove_dir_all(&docs));
         t!(fs::create_dir_all(&docs));
-        let target = "x86_64-unknown-linux-gnu";
-
-        // Unpack the regular documentation tarball.
-        let tarball_prefix = format!("rust-docs-{}-{}", version, target);
-        let tarball = format!("{}.tar.gz", self.dl_dir().join(&tarball_prefix).display());
-        let tarball_dir = format!("{}/rust-docs/share/doc/rust/html", tarball_prefix);
-        run(Command::new("tar")
-                    .arg("xf")
-                    .arg(&tarball)
-                    .arg("--strip-components=6")
-                    .arg(&tarball_dir)
-                    .current_dir(&docs));
-
-        // Construct path to rustc documentation.
-        let tarball_prefix = format!("rustc-docs-{}-{}", version, target);
-        let tarball = format!("{}.tar.gz", self.dl_dir().join(&tarball_prefix).display());
-
-        // Only create and unpack rustc docs if artefacts include tarball.
-        if Path::new(&tarball).exists() {
-            let rustc_docs = docs.join("nightly-rustc");
-            t!(fs::create_dir_all(&rustc_docs));
-
-            // Construct the path that contains the documentation inside the tarball.
-            let tarball_dir = format!("{}/rustc-docs/share/doc/rust/html", tarball_prefix);
-            let tarball_dir_new = format!("{}/rustc", tarball_dir);
-
-            if t!(Command::new("tar")
-                .arg("tf")
-                .arg(&tarball)
-                .arg(&tarball_dir_new)
-                .current_dir(&rustc_docs)
-                .output())
-                .status
-                .success() {
-                // Unpack the rustc documentation into the new directory.
-                run(Command::new("tar")
-                    .arg("xf")
-                    .arg(&tarball)
-                    .arg("--strip-components=7")
-                    .arg(&tarball_dir_new)
-                    .current_dir(&rustc_docs));
-            } else {
-                // Unpack the rustc documentation into the new directory.
-                run(Command::new("tar")
-                    .arg("xf")
-                    .arg(&tarball)
-                    .arg("--strip-components=6")
-                    .arg(&tarball_dir)
-                    .current_dir(&rustc_docs));
-            }
+        let targets = self.doc_targets();
+        let published = match docs::extract_targets(&self.dl_dir(), version, upload_dir,
+                                                    &targets, &docs) {
+            Ok(published) => published,
+            Err(e) => panic!("failed to extract docs: {}", e),
+        };
 
-        }
+        let mut skip = published[1..].iter().map(|t| &t[..]).collect::<Vec<_>>();
+        skip.push("targets.html");
+        let name = format!("rust-docs-html-{}", version);
+        let bundle = self.dl_dir().join(format!("{}.tar.xz", name));
+        println!("bundling docs into {}", bundle.display());
+        t!(docs::bundle(&docs, &name, &bundle, &skip));
+    }
+
+    fn publish_docs(&mut self, rev: &str) {
+        let upload_dir = &self.release[..];
+        let version = &self.docs_version()[..];
+        let docs = self.work.join("docs");
 
         // Upload this to `/doc/$channel`
-        let bucket = self.secrets["dist"]["upload-bucket"].as_str().unwrap();
-        let dst = format!("s3://{}/doc/{}/", bucket, upload_dir);
-        run(self.aws_s3()
-                .arg("sync")
-                .arg("--delete")
-                .arg("--only-show-errors")
-                .arg(format!("{}/", docs.display()))
-                .arg(&dst));
-        self.invalidate_docs(upload_dir);
+        let changed = self.publish_docs_dir(&docs, upload_dir);
+        self.invalidate_docs(upload_dir, &changed);
 
         // Stable artifacts also go to `/doc/$version/
-        if upload_dir == "stable" {
-            let dst = format!("s3://{}/doc/{}/", bucket, version);
-            run(self.aws_s3()
-                    .arg("sync")
-                    .arg("--delete")
-                    .arg("--only-show-errors")
-                    .arg(format!("{}/", docs.display()))
-                    .arg(&dst));
-            self.invalidate_docs(&version);
+        if self.channel == "stable" {
+            let changed = self.publish_docs_dir(&docs, version);
+            self.invalidate_docs(&version, &changed);
+            self.publish_versions_index(rev);
+        }
+    }
+
+    /// Regenerates `/doc/versions.html` and `/doc/versions.json` listing all
+    /// the `/doc/$version/` directories in the bucket, along with redirect
+    /// stubs from `/doc/$major.$minor/` to the newest patch release of each.
+    fn publish_versions_index(&self, rev: &str) {
+        let storage = self.storage();
+        let mut dirs = t!(storage.list_dirs("doc/"));
+        let current = self.current_version.clone().unwrap();
+        dirs.push(current.clone());
+
+        // Release dates come from the headings in `RELEASES.md`, falling back
+        // to what the previous index said, and to today for this release.
+        let mut dates = BTreeMap::new();
+        let previous = self.work.join("versions.json");
+        if t!(storage.download_file("doc/versions.json", &previous)) {
+            let json = t!(fs::read_to_string(&previous));
+            let previous: serde_json::Value = t!(serde_json::from_str(&json));
+            for v in previous.as_array().expect("versions.json not an array") {
+                if let (Some(version), Some(date)) = (v["version"].as_str(), v["date"].as_str()) {
+                    dates.insert(version.to_string(), date.to_string());
+                }
+            }
+        }
+        if let Ok(releases) = self.releases_md(rev) {
+            dates.extend(release_notes::release_dates(&releases));
+        }
+        dates.entry(current).or_insert_with(|| self.date.clone());
+        let versions = versions::collect(&dirs, &dates);
+        println!("{} versions of the docs published", versions.len());
+
+        let index = self.work.join("versions-index");
+        drop(fs::remove_dir_all(&index));
+        t!(fs::create_dir_all(&index));
+        t!(t!(File::create(index.join("versions.json")))
+            .write_all(versions::to_json(&versions).as_bytes()));
+        t!(t!(File::create(index.join("versions.html")))
+            .write_all(versions::to_html(&versions).as_bytes()));
+        let mut paths = vec!["/versions.json".to_string(), "/versions.html".to_string()];
+        for (minor, latest) in versions::latest_patches(&versions) {
+            t!(fs::create_dir_all(index.join(&minor)));
+            t!(t!(File::create(index.join(&minor).join("index.html")))
+                .write_all(versions::redirect_stub(&latest).as_bytes()));
+            paths.push(format!("/{}/", minor));
+            paths.push(format!("/{}/index.html", minor));
+        }
+        t!(storage.upload_dir(&index, "doc/"));
+        self.invalidate("docs", paths, "/*");
+    }
+
+    /// Targets whose docs are published, configured with `dist.doc-targets`.
+    ///
+    /// The first one is the primary target whose docs go at the root of the
+    /// channel's docs. Defaults to just x86_64-unknown-linux-gnu.
+    fn doc_targets(&self) -> Vec<String> {
+        let targets = match self.secrets["dist"].get("doc-targets") {
+            Some(targets) => targets.as_array()
+                .expect("doc-targets not an array")
+                .iter()
+                .map(|t| t.as_str().expect("doc target not a string").to_string())
+                .collect::<Vec<_>>(),
+            None => vec!["x86_64-unknown-linux-gnu".to_string()],
+        };
+        assert!(!targets.is_empty(), "doc-targets is empty");
+        targets
+    }
+
+    /// Publishes the docs in `docs` to `/doc/$dir`, returning the keys
+    /// relative to that directory that were uploaded or deleted.
+    ///
+    /// Content hashes of what was last published to `dir` are kept in
+    /// `/doc-manifests/$dir.json` in storage, so only files whose contents
+    /// changed get uploaded and only files that vanished get deleted. If
+    /// there's no such manifest yet, or it's unreadable, the whole tree is
+    /// synced instead.
+    fn publish_docs_dir(&self, docs: &Path, dir: &str) -> Vec<String> {
+        t!(self.sync_docs(&*self.storage(), "", docs, dir))
+    }
+
+    /// Publishes `docs` to `${prefix}doc/$dir` in `storage` the way
+    /// `publish_docs_dir` describes, keeping the manifest in
+    /// `${prefix}doc-manifests/$dir.json`.
+    fn sync_docs(&self, storage: &dyn Storage, prefix: &str, docs: &Path, dir: &str)
+        -> storage::Result<Vec<String>>
+    {
+        let dst = format!("{}doc/{}/", prefix, dir);
+        let manifest_key = format!("{}doc-manifests/{}.json", prefix, dir);
+        storage::sync_tree(storage, docs, &dst, &manifest_key,
+                           &self.work.join("docs-manifest.json"))
+    }
+
+    /// Invalidates the docs in `/doc/$dir` that changed, given as `keys`
+    /// relative to that directory.
+    ///
+    /// Stable docs are also served from the root of the docs domain, so those
+    /// paths get invalidated as well.
+    fn invalidate_docs(&self, dir: &str, keys: &[String]) {
+        let mut paths = Vec::new();
+        for key in keys {
+            for key in cdn::cached_paths(key) {
+                paths.push(format!("/{}/{}", dir, key));
+                if dir == "stable" {
+                    paths.push(format!("/{}", key));
+                }
+            }
+        }
+        let wildcard = if dir == "stable" {
+            "/*".to_string()
+        } else {
+            format!("/{}/*", dir)
+        };
+        self.invalidate("docs", paths, &wildcard);
+    }
+
+    fn publish_release(&mut self) {
+        let dir = self.secrets["dist"]["upload-dir"].as_str().unwrap();
+        t!(self.storage().upload_dir(&self.dl_dir(), &format!("{}/", dir)));
+        let paths = self.uploaded_paths(&format!("/{}", dir));
+        self.invalidations.extend(paths);
+    }
+
+    /// Checks that everything in `dl_dir` made it to both the dated archive
+    /// and the live directory with the right sizes and contents, and that
+    /// every URL in the manifest just published resolves, returning every
+    /// problem found.
+    ///
+    /// The objects are listed in the storage itself. The URLs are requested
+    /// through the CDN, which is only up to date once `invalidate_static` is
+    /// done.
+    fn verify_published(&mut self) -> Vec<String> {
+        let dir = self.secrets["dist"]["upload-dir"].as_str().unwrap().to_string();
+        let expected = self.expected_objects();
+
+        let storage = self.storage();
+        let mut problems = Vec::new();
+        for prefix in &[format!("{}/{}/", dir, self.date), format!("{}/", dir)] {
+            match self.check_objects(&*storage, prefix, &expected) {
+                Ok(found) => problems.extend(found),
+                Err(e) => problems.push(format!("failed to check {}: {}", prefix, e)),
+            }
+        }
+
+        let manifest = self.dl_dir().join(format!("channel-rust-{}.toml", self.channel));
+        let manifest = t!(t!(fs::read_to_string(&manifest)).parse());
+        let urls = verify::manifest_urls(&manifest);
+        println!("checking that {} urls in the live manifest resolve", urls.len());
+        for url in urls {
+            if !self.url_exists(&url) {
+                problems.push(format!("{} doesn't resolve", url));
+            }
+        }
+        problems
+    }
+
+    /// Every file in `dl_dir` with its size and hash from `SHA256SUMS`, which
+    /// is what each directory it's published to should end up holding.
+    fn expected_objects(&self) -> Vec<verify::Expected> {
+        let dl = self.dl_dir();
+        let sums = hashes::parse_sums(&t!(fs::read_to_string(dl.join("SHA256SUMS"))));
+        t!(dl.read_dir()).map(|e| {
+            let e = t!(e);
+            let name = e.file_name().into_string().unwrap();
+            verify::Expected {
+                size: t!(e.metadata()).len(),
+                sha256: sums.get(&name).cloned(),
+                name,
+            }
+        }).collect()
+    }
+
+    /// Checks that the objects under `prefix` in `storage` are the `expected`
+    /// ones, by their sizes and by the hashes in the `SHA256SUMS` published
+    /// along with them, returning every problem found.
+    fn check_objects(&self, storage: &dyn Storage, prefix: &str, expected: &[verify::Expected])
+        -> storage::Result<Vec<String>>
+    {
+        let mut problems = verify::compare(prefix, expected, storage.list(prefix)?);
+        let key = format!("{}SHA256SUMS", prefix);
+        let sums = self.work.join("published-sha256sums");
+        // If it's not there at all `compare` already said so.
+        if storage.download_file(&key, &sums)? {
+            problems.extend(verify::compare_sums(&key, &fs::read_to_string(&sums)?, expected));
+        }
+        Ok(problems)
+    }
+
+    /// The mirrors configured in `dist.mirrors`, which are set up before
+    /// anything else so a broken configuration is reported before the release
+    /// is published rather than after. Those mirrors are skipped.
+    fn mirrors(&self) -> Vec<Mirror> {
+        let dist = &self.secrets["dist"];
+        mirror::from_config(dist.get("mirrors"), dist, &self.work)
+    }
+
+    /// Publishes everything that just went to the primary storage to each of
+    /// `mirrors` as well.
+    ///
+    /// By now the release is out, so a mirror failing doesn't fail it, nor
+    /// does it stop the other mirrors. How each one went is printed and
+    /// recorded in `mirror-status-$release.json` next to the live manifest.
+    fn publish_mirrors(&self, mirrors: &mut [Mirror]) {
+        if mirrors.is_empty() {
+            return
+        }
+        let expected = self.expected_objects();
+        let mut statuses = Vec::new();
+        let mut failed = 0;
+        for mirror in mirrors.iter_mut() {
+            println!("publishing to mirror {}", mirror.name);
+            let start = Instant::now();
+            let result = self.publish_mirror(mirror, &expected);
+            let seconds = start.elapsed().as_secs();
+            let error = match result {
+                Ok(()) => {
+                    println!("mirror {} published in {}s", mirror.name, seconds);
+                    None
+                }
+                Err(e) => {
+                    println!("mirror {} failed after {}s: {}", mirror.name, seconds, e);
+                    failed += 1;
+                    Some(e.to_string())
+                }
+            };
+            statuses.push(json!({
+                "name": mirror.name,
+                "ok": error.is_none(),
+                "error": error,
+                "seconds": seconds,
+            }));
+        }
+        println!("published to {} of {} mirrors", mirrors.len() - failed, mirrors.len());
+
+        let dir = self.secrets["dist"]["upload-dir"].as_str().unwrap();
+        let key = format!("{}/mirror-status-{}.json", dir, self.release);
+        let status = json!({
+            "release": self.release,
+            "date": self.date,
+            "mirrors": statuses,
+        });
+        let path = self.work.join("mirror-status.json");
+        t!(t!(File::create(&path)).write_all(status.to_string().as_bytes()));
+        if let Err(e) = self.storage().upload_file(&path, &key) {
+            println!("failed to upload {}: {}", key, e);
         }
     }
 
-    fn invalidate_docs(&self, dir: &str) {
-        let distribution_id = self.secrets["dist"]["rustdoc-cf-distribution-id"]
-                                          .as_str().unwrap();
+    /// Publishes the artifacts, docs and manifests to `mirror` in the same
+    /// order as to the primary storage, so its live manifests only change
+    /// once everything they point to is there. Then checks the artifacts all
+    /// made it and purges whatever changed from the mirror's CDN, if any.
+    fn publish_mirror(&self, mirror: &mut Mirror, expected: &[verify::Expected])
+        -> Result<(), Box<dyn Error>>
+    {
+        let dir = self.secrets["dist"]["upload-dir"].as_str().unwrap();
+        let dl = self.dl_dir();
+        let dated = mirror.key(&format!("{}/{}/", dir, self.date));
+        let live = mirror.key(&format!("{}/", dir));
+        let mut paths = Vec::new();
+
+        mirror.storage.upload_dir(&dl, &dated)?;
+
+        let docs = self.work.join("docs");
+        let mut doc_dirs = vec![self.release.clone()];
+        if self.channel == "stable" {
+            doc_dirs.push(self.docs_version());
+        }
+        for doc_dir in doc_dirs {
+            let prefix = mirror.key(&format!("doc/{}/", doc_dir));
+            for key in self.sync_docs(&*mirror.storage, &mirror.prefix, &docs, &doc_dir)? {
+                for key in cdn::cached_paths(&key) {
+                    paths.push(format!("/{}{}", prefix, key));
+                }
+            }
+        }
+        if self.channel == "stable" {
+            // The index `publish_versions_index` built for the primary storage.
+            let index = self.work.join("versions-index");
+            mirror.storage.upload_dir(&index, &mirror.key("doc/"))?;
+            for entry in index.read_dir()? {
+                let entry = entry?;
+                let name = entry.file_name().into_string().unwrap();
+                let key = mirror.key(&format!("doc/{}", name));
+                if entry.file_type()?.is_dir() {
+                    paths.push(format!("/{}/", key));
+                    paths.push(format!("/{}/index.html", key));
+                } else {
+                    paths.push(format!("/{}", key));
+                }
+            }
+        }
+
+        mirror.storage.upload_dir(&dl, &live)?;
+
+        let mut problems = Vec::new();
+        for prefix in &[&dated, &live] {
+            problems.extend(self.check_objects(&*mirror.storage, prefix, expected)?);
+            paths.extend(expected.iter().map(|e| format!("/{}{}", prefix, e.name)));
+        }
+        if !problems.is_empty() {
+            return Err(format!("verification failed:\n{}", problems.join("\n")).into())
+        }
+
+        let wildcard = format!("/{}*", mirror.prefix);
+        if let Some(ref mut cdn) = mirror.cdn {
+            paths.sort();
+            paths.dedup();
+            self.purge(&mut **cdn, &paths, &wildcard)?;
+        }
+        Ok(())
+    }
+
+    /// Installs the toolchain in the live manifest for
+    /// `dist.smoke-test-target` the way rustup would, to make sure what we
+    /// just published actually works, returning what went wrong if it
+    /// doesn't.
+    ///
+    /// That goes through the CDN, which is only up to date once
+    /// `invalidate_static` is done.
+    fn smoke_test(&mut self) -> Option<String> {
+        let url = self.manifest_url();
+        let target = self.secrets["dist"].get("smoke-test-target")
+            .map(|t| t.as_str().expect("smoke-test-target not a string"))
+            .unwrap_or("x86_64-unknown-linux-gnu");
+        let dir = self.work.join("smoke");
+        let verifier = self.verifier();
+        let result = smoke::run(&url, target, &dir, &verifier, &mut self.http);
+        drop(fs::remove_dir_all(&dir));
+        match result {
+            Ok(version) => {
+                println!("smoke test installed {}", version);
+                None
+            }
+            Err(e) => {
+                let problem = format!("smoke test of {} for {} failed: {}", url, target, e);
+                println!("{}", problem);
+                Some(problem)
+            }
+        }
+    }
+
+    /// Verifier of signatures made with the release key, whose public half
+    /// is configured with `dist.gpg-public-key`. Without it the public half
+    /// of the key in the local GnuPG home releases are signed with is used.
+    fn verifier(&self) -> gpg::Verifier {
+        let dir = self.work.join("gpg");
+        match self.secrets["dist"].get("gpg-public-key") {
+            Some(key) => {
+                let key = key.as_str().expect("gpg-public-key not a string");
+                t!(gpg::Verifier::new(Path::new(key), &dir))
+            }
+            None => t!(gpg::Verifier::from_secret_keys(&dir)),
+        }
+    }
+
+    /// Lists all the objects under `prefix` in the CI bucket `bucket`, keyed
+    /// by their key.
+    fn list_objects(&self, bucket: &str, prefix: &str) -> BTreeMap<String, verify::Object> {
         let mut cmd = Command::new("aws");
         self.aws_creds(&mut cmd);
-        cmd.arg("cloudfront")
-            .arg("create-invalidation")
-            .arg("--distribution-id").arg(distribution_id);
-        if dir == "stable" {
-            cmd.arg("--paths").arg("/*");
+        let listing = output(cmd.arg("s3api")
+                                .arg("list-objects-v2")
+                                .arg("--bucket").arg(bucket)
+                                .arg("--prefix").arg(prefix)
+                                .arg("--output").arg("text")
+                                .arg("--query").arg("Contents[].[Key,Size,ETag]"));
+        verify::parse_listing(&listing).into_iter().map(|o| (o.key.clone(), o)).collect()
+    }
+
+    /// Paths under `prefix` of all the files in `dl_dir`, which is what
+    /// uploading it recursively to `prefix` touches.
+    fn uploaded_paths(&self, prefix: &str) -> Vec<String> {
+        t!(self.dl_dir().read_dir()).map(|e| {
+            let name = t!(e).file_name().into_string().unwrap();
+            format!("{}/{}", prefix, name)
+        }).collect()
+    }
+
+    /// Purges everything published from the static CDN, and waits for that
+    /// to be done so that what's requested through it afterwards is the new
+    /// release.
+    fn invalidate_static(&mut self) -> cdn::Result {
+        let dir = self.secrets["dist"]["upload-dir"].as_str().unwrap();
+        let paths = self.invalidations.clone();
+        let mut cdn = self.cdn("static");
+        self.invalidate_in(&mut *cdn, "static", paths, &format!("/{}/*", dir));
+        cdn.wait()
+    }
+
+    /// Purges exactly `paths` from the CDN of the publication `target`.
+    ///
+    /// Past `dist.invalidation-threshold` paths (1000 by default) it's cheaper
+    /// to purge everything, so just `wildcard` is purged instead.
+    fn invalidate(&self, target: &str, paths: Vec<String>, wildcard: &str) {
+        self.invalidate_in(&mut *self.cdn(target), target, paths, wildcard)
+    }
+
+    /// Like `invalidate`, with `cdn` being the CDN of `target`.
+    fn invalidate_in(&self, cdn: &mut dyn Cdn, target: &str, mut paths: Vec<String>,
+                     wildcard: &str) {
+        paths.sort();
+        paths.dedup();
+        if paths.is_empty() {
+            return println!("nothing to invalidate for {}", target)
+        }
+        t!(self.purge(cdn, &paths, wildcard));
+    }
+
+    /// Purges `paths` from `cdn`, or everything matching `wildcard` if there
+    /// are more of them than `dist.invalidation-threshold`.
+    fn purge(&self, cdn: &mut dyn Cdn, paths: &[String], wildcard: &str) -> cdn::Result {
+        let threshold = self.secrets["dist"].get("invalidation-threshold")
+            .map(|t| t.as_integer().expect("invalidation-threshold not an integer"))
+            .unwrap_or(1000);
+        if paths.len() as i64 > threshold {
+            println!("{} paths changed, invalidating {} instead", paths.len(), wildcard);
+            cdn.purge_wildcard(wildcard)
         } else {
-            cmd.arg("--paths").arg(format!("/{0}/*", dir));
+            cdn.purge_paths(paths)
         }
-        run(&mut cmd);
     }
 
-    fn publish_release(&mut self) {
-        let bucket = self.secrets["dist"]["upload-bucket"].as_str().unwrap();
-        let dir = self.secrets["dist"]["upload-dir"].as_str().unwrap();
-        let dst = format!("s3://{}/{}/", bucket, dir);
-        run(self.aws_s3()
-                .arg("cp")
-                .arg("--recursive")
-                .arg("--only-show-errors")
-                .arg(format!("{}/", self.dl_dir().display()))
-                .arg(&dst));
+    /// The CDN in front of the publication `target`, either `static` or
+    /// `docs`, as configured in `dist.cdn.$target`.
+    ///
+    /// Without any configuration this falls back to the CloudFront
+    /// distributions in `dist.cloudfront-distribution-id` and
+    /// `dist.rustdoc-cf-distribution-id` respectively.
+    fn cdn(&self, target: &str) -> Box<dyn Cdn> {
+        let dist = &self.secrets["dist"];
+        if let Some(config) = dist.get("cdn").and_then(|c| c.get(target)) {
+            return cdn::from_config(config, dist, &self.work)
+                .unwrap_or_else(|e| panic!("bad {} CDN: {}", target, e))
+        }
+        let id_key = match target {
+            "static" => "cloudfront-distribution-id",
+            "docs" => "rustdoc-cf-distribution-id",
+            _ => panic!("unknown publication target: {}", target),
+        };
+        Box::new(cdn::CloudFront {
+            distribution_id: dist[id_key].as_str().unwrap().to_string(),
+            access_key: dist["aws-access-key-id"].as_str().unwrap().to_string(),
+            secret_key: dist["aws-secret-key"].as_str().unwrap().to_string(),
+            payload: self.work.join("payload.json"),
+            pending: Vec::new(),
+        })
     }
 
-    fn invalidate_cloudfront(&mut self) {
-        let json = json!({
-            "Paths": {
-                "Items": [
-                    "/dist/*",
-                ],
-                "Quantity": 4,
-            },
-            "CallerReference": format!("rct-{}", rand::random::<usize>()),
-        }).to_string();
-        let dst = self.work.join("payload.json");
-        t!(t!(File::create(&dst)).write_all(json.as_bytes()));
-
-        let distribution_id = self.secrets["dist"]["cloudfront-distribution-id"]
-                                          .as_str().unwrap();
+    /// The storage releases are published to, configured by the
+    /// `[dist.storage]` table or the S3 bucket in `dist.upload-bucket` by
+    /// default.
+    fn storage(&self) -> Box<dyn Storage> {
+        let dist = &self.secrets["dist"];
+        if let Some(config) = dist.get("storage") {
+            return storage::from_config(config, dist, &self.work)
+                .unwrap_or_else(|e| panic!("bad storage: {}", e))
+        }
+        Box::new(storage::S3 {
+            bucket: dist["upload-bucket"].as_str().unwrap().to_string(),
+            access_key: dist["aws-access-key-id"].as_str().unwrap().to_string(),
+            secret_key: dist["aws-secret-key"].as_str().unwrap().to_string(),
+            work: self.work.clone(),
+            policy: metadata::Policy::from_config(dist.get("metadata")),
+        })
+    }
+
+    fn upstream_repo(&self) -> &str {
+        self.secrets["dist"].get("upstream-repo")
+            .map(|u| u.as_str().expect("upstream-repo not a string"))
+            .unwrap_or("https://github.com/rust-lang/rust")
+    }
+
+    /// HTTP URL the artifact `key` in `bucket` is downloaded from. That's
+    /// under `dist.source-url` if set, for example to go through a CDN, or
+    /// the bucket's S3 endpoint in `region` otherwise, presigned by
+    /// `aws s3 presign` with the AWS credentials so private buckets work too.
+    fn source_http_url(&self, bucket: &str, key: &str, region: &str) -> String {
+        if let Some(url) = self.secrets["dist"].get("source-url") {
+            let url = url.as_str().expect("source-url not a string");
+            return format!("{}/{}", url.trim_end_matches('/'), http::encode_path(key))
+        }
+        // Not `output`, which would log the credentials for every artifact.
+        let mut cmd = self.aws_s3();
+        cmd.arg("presign").arg(format!("s3://{}/{}", bucket, key))
+           .arg("--region").arg(region)
+           .arg("--expires-in").arg("86400");
+        let out = t!(cmd.output());
+        if !out.status.success() {
+            panic!("failed to presign s3://{}/{}: {}\n{}", bucket, key, out.status,
+                   String::from_utf8_lossy(&out.stderr));
+        }
+        t!(String::from_utf8(out.stdout)).trim().to_string()
+    }
+
+    /// Region of the source `bucket`, from `dist.source-region` or else as
+    /// reported by S3, which answers `None` for buckets in `us-east-1`.
+    fn source_region(&self, bucket: &str) -> String {
+        if let Some(region) = self.secrets["dist"].get("source-region") {
+            return region.as_str().expect("source-region not a string").to_string()
+        }
         let mut cmd = Command::new("aws");
         self.aws_creds(&mut cmd);
-        run(cmd.arg("cloudfront")
-               .arg("create-invalidation")
-               .arg("--invalidation-batch").arg(format!("file://{}", dst.display()))
-               .arg("--distribution-id").arg(distribution_id));
+        let region = output(cmd.arg("s3api")
+                               .arg("get-bucket-location")
+                               .arg("--bucket").arg(bucket)
+                               .arg("--query").arg("LocationConstraint")
+                               .arg("--output").arg("text"));
+        match region.trim() {
+            "" | "None" | "null" => "us-east-1".to_string(),
+            region => region.to_string(),
+        }
+    }
+
+    /// How artifacts are downloaded, configured through
+    /// `dist.download-parallelism` and `dist.download-rate-limit` (in MiB/s,
+    /// unlimited by default).
+    fn download_options(&self) -> download::Options {
+        let dist = &self.secrets["dist"];
+        download::Options {
+            parallelism: dist.get("download-parallelism")
+                .map(|p| p.as_integer().expect("download-parallelism not an integer") as usize)
+                .unwrap_or(8),
+            max_rate: dist.get("download-rate-limit")
+                .map(|r| (r.as_integer().expect("download-rate-limit not an integer") as u64) << 20),
+        }
+    }
+
+    /// Size in bytes the artifact cache is trimmed to after each download,
+    /// configured in GiB through `dist.artifact-cache-size`.
+    fn artifact_cache_size(&self) -> u64 {
+        let gib = self.secrets["dist"].get("artifact-cache-size")
+            .map(|s| s.as_integer().expect("artifact-cache-size not an integer") as u64)
+            .unwrap_or(40);
+        gib << 30
+    }
+
+    /// Location in the CI bucket of the artifacts built for `rev`, configured
+    /// through `dist.source-bucket` and `dist.source-dir`.
+    fn source_url(&self, rev: &str) -> String {
+        let (bucket, prefix) = self.source_location(rev);
+        format!("s3://{}/{}", bucket, prefix)
+    }
+
+    /// The bucket CI uploads the artifacts of `rev` to, and their prefix in
+    /// it.
+    fn source_location(&self, rev: &str) -> (String, String) {
+        let bucket = self.secrets["dist"].get("source-bucket")
+            .map(|b| b.as_str().expect("source-bucket not a string"))
+            .unwrap_or("rust-lang-ci2");
+        let dir = self.secrets["dist"].get("source-dir")
+            .map(|d| d.as_str().expect("source-dir not a string"))
+            .unwrap_or("rustc-builds");
+        (bucket.to_string(), format!("{}/{}/", dir, rev))
     }
 
     fn rust_dir(&self) -> PathBuf {
-        self.work.join("rust")
+        match self.local_build {
+            Some(ref checkout) => checkout.clone(),
+            None => self.work.join("rust"),
+        }
+    }
+
+    fn repo(&self) -> Repository {
+        t!(Repository::open(self.rust_dir()))
     }
 
     fn dl_dir(&self) -> PathBuf {
@@ -565,58 +1530,104 @@ upload-addr = \"{}/{}\"
     }
 
     fn dated_manifest_exists(&mut self) -> bool {
-        self.handle.reset();
-        t!(self.handle.get(true));
         let addr = self.secrets["dist"]["upload-addr"].as_str().unwrap();
         let upload_dir = self.secrets["dist"]["upload-dir"].as_str().unwrap();
         let url = format!("{}/{}/{}/channel-rust-{}.toml",
                           addr,
                           upload_dir,
                           self.date,
-                          self.release);
+                          self.channel);
         println!("checking if manifest exists: {}", url);
-        t!(self.handle.url(&url));
-        let mut result = Vec::new();
-        {
-            let mut t = self.handle.transfer();
-
-            t!(t.write_function(|data| {
-                result.extend_from_slice(data);
-                Ok(data.len())
-            }));
-            t!(t.perform());
-        }
-        match t!(self.handle.response_code()) {
+        match t!(self.http.head(&url)) {
             200 => true,
             404 => false,
             other => panic!("unexpected response code: {}", other),
         }
     }
 
-    fn download_manifest(&mut self) -> toml::Value {
-        self.handle.reset();
-        t!(self.handle.get(true));
+    fn url_exists(&mut self, url: &str) -> bool {
+        match self.http.head(url) {
+            Ok(code) => code == 200,
+            Err(e) => {
+                println!("failed to check {}: {}", url, e);
+                false
+            }
+        }
+    }
+
+    /// URL of the live manifest of the channel we're releasing.
+    fn manifest_url(&self) -> String {
         let addr = self.secrets["dist"]["upload-addr"].as_str().unwrap();
         let upload_dir = self.secrets["dist"]["upload-dir"].as_str().unwrap();
-        let url = format!("{}/{}/channel-rust-{}.toml",
-                          addr,
-                          upload_dir,
-                          self.release);
+        format!("{}/{}/channel-rust-{}.toml", addr, upload_dir, self.channel)
+    }
+
+    /// Downloads the live manifest of the channel we're releasing, along
+    /// with its `.sha256` and `.asc`, and checks both before trusting any of
+    /// its contents. Returns `None` if there's no live manifest at all, and
+    /// panics if it still doesn't check out after `LIVE_MANIFEST_ATTEMPTS`.
+    fn download_manifest(&mut self) -> Option<toml::Value> {
+        let url = self.manifest_url();
         println!("downloading manifest from: {}", url);
-        t!(self.handle.url(&url));
-        let mut result = Vec::new();
-        {
-            let mut t = self.handle.transfer();
-
-            t!(t.write_function(|data| {
-                result.extend_from_slice(data);
-                Ok(data.len())
-            }));
-            t!(t.perform());
+        let dir = self.work.join("live-manifest");
+        let verifier = self.verifier();
+        let manifest = t!(verify::live_manifest(&mut self.http, &url, &verifier, &dir,
+                                                LIVE_MANIFEST_ATTEMPTS,
+                                                Duration::from_secs(60)))?;
+        Some(t!(t!(String::from_utf8(manifest)).parse()))
+    }
+}
+
+/// Looks up which branch of the upstream repository `release` is built from,
+/// which rustbuild release channel it's built as, and which `upload-dir` it's
+/// published to if that's not the one in `dist`.
+///
+/// The usual nightly/beta/stable mapping is built in, but it can be overridden
+/// and extra channels added through the `dist.channels` table, where each
+/// entry is either just a branch name or a table with `branch`,
+/// `release-channel` and `upload-dir` keys. Manifests are named after the
+/// rustbuild channel, so a release built as another channel than its own name
+/// has to be published to an `upload-dir` of its own, or it would overwrite
+/// that channel's manifest.
+fn channel_config(dist: &toml::Value, release: &str) -> (String, String, Option<String>) {
+    let default_branch = match release {
+        "nightly" => Some("master"),
+        "beta" => Some("beta"),
+        "stable" => Some("stable"),
+        _ => None,
+    };
+    let configured = dist.get("channels").and_then(|c| c.get(release));
+    let (branch, channel, upload_dir) = match configured {
+        Some(config) if config.is_str() => (config.as_str().unwrap(), release, None),
+        Some(config) => {
+            let branch = config.get("branch")
+                .map(|b| b.as_str().expect("channel branch not a string"))
+                .or(default_branch)
+                .unwrap_or_else(|| panic!("channel {} has no branch", release));
+            let channel = config.get("release-channel")
+                .map(|c| c.as_str().expect("release-channel not a string"))
+                .unwrap_or(release);
+            let upload_dir = config.get("upload-dir")
+                .map(|d| d.as_str().expect("channel upload-dir not a string").to_string());
+            (branch, channel, upload_dir)
         }
-        assert_eq!(t!(self.handle.response_code()), 200);
-        t!(t!(String::from_utf8(result)).parse())
+        None => {
+            let branch = default_branch
+                .unwrap_or_else(|| panic!("unknown release: {}", release));
+            (branch, release, None)
+        }
+    };
+    match channel {
+        "nightly" | "beta" | "stable" => {}
+        _ => panic!("unknown release channel {} for {}, it needs a release-channel \
+                     of nightly, beta or stable", channel, release),
+    }
+    let shared_dir = dist["upload-dir"].as_str().unwrap();
+    if channel != release && upload_dir.as_ref().is_none_or(|d| d == shared_dir) {
+        panic!("{} would overwrite the {} manifest in {}, it needs an upload-dir \
+                of its own", release, channel, shared_dir);
     }
+    (branch.to_string(), channel.to_string(), upload_dir)
 }
 
 fn run(cmd: &mut Command) {
@@ -627,6 +1638,24 @@ fn run(cmd: &mut Command) {
     }
 }
 
+/// The commit the local build in `checkout` is of. That's what its
+/// `git-commit-hash` says if it was unpacked from a source tarball, or else
+/// what's checked out if it's a git repository at all. Nothing is fetched for
+/// local builds, so when it's neither the rev is only reported as unknown.
+fn local_rev(checkout: &Path) -> String {
+    if let Ok(hash) = fs::read_to_string(checkout.join("git-commit-hash")) {
+        return hash.trim().to_string()
+    }
+    match Repository::open(checkout) {
+        Ok(repo) => t!(git::head_rev(&repo)).to_string(),
+        Err(_) => {
+            println!("{} is neither a git repository nor has a git-commit-hash",
+                     checkout.display());
+            "unknown".to_string()
+        }
+    }
+}
+
 fn output(cmd: &mut Command) -> String {
     println!("running {:?}", cmd);
     let output = t!(cmd.output());
@@ -638,3 +1667,127 @@ fn output(cmd: &mut Command) -> String {
 
     String::from_utf8(output.stdout).unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+
+    fn config(channels: &str, release: &str) -> (String, String, Option<String>) {
+        let dist = format!("upload-dir = \"dist\"\n[channels]\n{}", channels);
+        channel_config(&dist.parse().unwrap(), release)
+    }
+
+    #[test]
+    fn built_in_channels() {
+        assert_eq!(config("", "nightly"), ("master".to_string(), "nightly".to_string(), None));
+        assert_eq!(config("", "stable"), ("stable".to_string(), "stable".to_string(), None));
+        assert_eq!(config("beta = \"next\"", "beta"),
+                   ("next".to_string(), "beta".to_string(), None));
+    }
+
+    #[test]
+    fn extra_channels() {
+        let channels = "internal = { branch = \"internal\", release-channel = \"nightly\", \
+                        upload-dir = \"internal\" }";
+        assert_eq!(config(channels, "internal"),
+                   ("internal".to_string(), "nightly".to_string(), Some("internal".to_string())));
+    }
+
+    #[test]
+    #[should_panic(expected = "unknown release: foo")]
+    fn rejects_unknown_releases() {
+        config("", "foo");
+    }
+
+    #[test]
+    #[should_panic(expected = "unknown release channel foo")]
+    fn rejects_extra_channels_given_as_branches() {
+        config("foo = \"branch\"", "foo");
+    }
+
+    #[test]
+    #[should_panic(expected = "unknown release channel dev")]
+    fn rejects_unknown_release_channels() {
+        config("foo = { branch = \"b\", release-channel = \"dev\", upload-dir = \"foo\" }",
+               "foo");
+    }
+
+    #[test]
+    #[should_panic(expected = "foo would overwrite the nightly manifest in dist")]
+    fn rejects_extra_channels_sharing_a_manifest() {
+        config("foo = { branch = \"b\", release-channel = \"nightly\" }", "foo");
+    }
+
+    #[test]
+    #[should_panic(expected = "beta would overwrite the stable manifest in dist")]
+    fn rejects_built_in_channels_sharing_a_manifest() {
+        config("beta = { branch = \"b\", release-channel = \"stable\", upload-dir = \"dist\" }",
+               "beta");
+    }
+
+    #[test]
+    fn publishes_to_mirrors() {
+        let work = env::temp_dir().join(format!("main-mirror-{}", process::id()));
+        drop(fs::remove_dir_all(&work));
+        for (path, contents) in &[("dl/rust-nightly.tar.xz", "rust"),
+                                  ("docs/index.html", "index"),
+                                  ("docs/std/index.html", "std")] {
+            let path = work.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        let sums = hashes::sha256sums(&work.join("dl"), |_| true).unwrap();
+        fs::write(work.join("dl/SHA256SUMS"), sums).unwrap();
+
+        let secrets = format!("[dist]\nupload-dir = \"dist\"\n\
+                               [[dist.mirrors]]\nname = \"local\"\nprefix = \"rust\"\n\
+                               storage = {{ provider = \"local\", path = {:?} }}\n\
+                               cdn = {{ provider = \"recording\", path = {:?} }}\n",
+                              work.join("mirror"), work.join("purges"));
+        let secrets: toml::Value = secrets.parse().unwrap();
+        let context = Context {
+            work: work.clone(),
+            release: "nightly".to_string(),
+            channel: "nightly".to_string(),
+            bootstrap: false,
+            local_build: None,
+            apply: false,
+            http: http::Client::new(&secrets["dist"]),
+            secrets,
+            date: "2020-01-01".to_string(),
+            current_version: None,
+            report: serde_json::Map::new(),
+            invalidations: Vec::new(),
+        };
+        let mut mirrors = context.mirrors();
+        let expected = context.expected_objects();
+        context.publish_mirror(&mut mirrors[0], &expected).unwrap();
+
+        let mirror = work.join("mirror/rust");
+        for path in &["dist/2020-01-01/rust-nightly.tar.xz", "dist/rust-nightly.tar.xz",
+                      "dist/SHA256SUMS", "doc/nightly/std/index.html",
+                      "doc-manifests/nightly.json"] {
+            assert!(mirror.join(path).is_file(), "{} is missing", path);
+        }
+        assert_eq!(fs::read_to_string(work.join("purges")).unwrap(),
+                   "paths /rust/dist/2020-01-01/SHA256SUMS \
+                    /rust/dist/2020-01-01/rust-nightly.tar.xz \
+                    /rust/dist/SHA256SUMS /rust/dist/rust-nightly.tar.xz \
+                    /rust/doc/nightly/ /rust/doc/nightly/index.html \
+                    /rust/doc/nightly/std/ /rust/doc/nightly/std/index.html\n");
+
+        // Nothing is purged from a mirror that's missing something.
+        fs::remove_file(work.join("purges")).unwrap();
+        let mut missing = context.expected_objects();
+        missing.push(verify::Expected { name: "cargo-nightly.tar.xz".to_string(), size: 1,
+                                        sha256: None });
+        let err = context.publish_mirror(&mut mirrors[0], &missing).unwrap_err();
+        assert_eq!(err.to_string(), "verification failed:\n\
+                                     rust/dist/2020-01-01/cargo-nightly.tar.xz is missing\n\
+                                     rust/dist/cargo-nightly.tar.xz is missing");
+        assert!(!work.join("purges").exists());
+        fs::remove_dir_all(&work).unwrap();
+    }
+}
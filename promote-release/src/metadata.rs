@@ -0,0 +1,233 @@
+//! The `Cache-Control` and `Content-Type` each published object is uploaded
+//! with, chosen by matching its key against a list of patterns.
+//!
+//! The rules come from the `[[dist.metadata]]` tables in the secrets, each
+//! with a `pattern` and a `cache-control` and/or `content-type`, or from
+//! `DEFAULT_RULES` if there aren't any. For each header the first rule
+//! matching a key that sets it wins.
+
+use std::env;
+
+use toml::Value;
+
+/// (pattern, cache-control, content-type)
+const DEFAULT_RULES: &[(&str, Option<&str>, Option<&str>)] = &[
+    // Manifests are replaced with every release, so they must not be cached
+    // for long, including the dated ones which are replaced by further
+    // releases on the same day.
+    ("**/channel-rust-*", Some("public, max-age=300"), None),
+    // Everything else in a dated directory never changes once published,
+    // unless releasing more than once a day is allowed (see `Policy::new`).
+    // That's `dist/2020-01-01/` on the primary storage, but mirrors may put
+    // it under a prefix.
+    ("**/????-??-??/**", Some("public, max-age=31536000, immutable"), None),
+    ("**.toml", None, Some("application/toml")),
+    ("**.asc", None, Some("application/pgp-signature")),
+    ("**.sha256", None, Some("text/plain; charset=utf-8")),
+    ("**/SHA256SUMS", None, Some("text/plain; charset=utf-8")),
+    ("**.html", None, Some("text/html; charset=utf-8")),
+    ("**.json", None, Some("application/json")),
+    ("**.md", None, Some("text/markdown; charset=utf-8")),
+];
+
+/// The headers an object is uploaded with. `None` leaves it up to the
+/// storage, which for S3 means no `Cache-Control` and a `Content-Type`
+/// guessed from the extension.
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Metadata {
+    pub cache_control: Option<String>,
+    pub content_type: Option<String>,
+    /// Space-separated surrogate keys the object is tagged with, which is
+    /// how CDNs like Fastly purge whole directories at once.
+    pub surrogate_key: Option<String>,
+}
+
+struct Rule {
+    pattern: String,
+    metadata: Metadata,
+}
+
+pub struct Policy {
+    rules: Vec<Rule>,
+}
+
+impl Policy {
+    /// Reads the rules in `config`, the `dist.metadata` array of the
+    /// secrets, or uses the default ones if it's not given.
+    pub fn from_config(config: Option<&Value>) -> Policy {
+        let config = match config {
+            Some(config) => config.as_array().expect("metadata not an array"),
+            None => return Policy::default(),
+        };
+        let get = |rule: &Value, key: &str| {
+            rule.get(key).map(|v| {
+                v.as_str().unwrap_or_else(|| panic!("metadata {} not a string", key)).to_string()
+            })
+        };
+        Policy {
+            rules: config.iter().map(|rule| {
+                Rule {
+                    pattern: get(rule, "pattern").expect("metadata rule without a pattern"),
+                    metadata: Metadata {
+                        cache_control: get(rule, "cache-control"),
+                        content_type: get(rule, "content-type"),
+                        surrogate_key: None,
+                    },
+                }
+            }).collect(),
+        }
+    }
+
+    /// The metadata to upload `key` with.
+    ///
+    /// Whatever the rules say, objects are tagged with the names of the first
+    /// two directories they're in, like `dist 2020-01-01` or `doc nightly`,
+    /// which are the keys `cdn::Fastly` purges for a wildcard like `/dist/*`
+    /// or, on the docs domain, `/nightly/*`.
+    pub fn lookup(&self, key: &str) -> Metadata {
+        let mut metadata = Metadata::default();
+        let mut dirs = key.split('/').collect::<Vec<_>>();
+        dirs.pop();
+        dirs.truncate(2);
+        if !dirs.is_empty() {
+            metadata.surrogate_key = Some(dirs.join(" "));
+        }
+        for rule in self.rules.iter().filter(|r| matches(&r.pattern, key)) {
+            if metadata.cache_control.is_none() {
+                metadata.cache_control = rule.metadata.cache_control.clone();
+            }
+            if metadata.content_type.is_none() {
+                metadata.content_type = rule.metadata.content_type.clone();
+            }
+        }
+        metadata
+    }
+}
+
+/// What dated directories are cached for by default when they may be
+/// published to again the same day, which is as long as manifests are.
+const REPUBLISHED_DATED_CACHE_CONTROL: &str = "public, max-age=300";
+
+impl Policy {
+    /// The default rules. With `multiple_today` dated directories may be
+    /// republished, so instead of being cached forever they're only cached
+    /// as long as manifests are.
+    fn new(multiple_today: bool) -> Policy {
+        Policy {
+            rules: DEFAULT_RULES.iter().map(|&(pattern, cache_control, content_type)| {
+                let cache_control = match pattern {
+                    "**/????-??-??/**" if multiple_today => Some(REPUBLISHED_DATED_CACHE_CONTROL),
+                    _ => cache_control,
+                };
+                Rule {
+                    pattern: pattern.to_string(),
+                    metadata: Metadata {
+                        cache_control: cache_control.map(|s| s.to_string()),
+                        content_type: content_type.map(|s| s.to_string()),
+                        surrogate_key: None,
+                    },
+                }
+            }).collect(),
+        }
+    }
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy::new(env::var("PROMOTE_RELEASE_ALLOW_MULTIPLE_TODAY").is_ok())
+    }
+}
+
+/// Matches `key` against the glob `pattern`, where `**` matches anything,
+/// `*` anything but a `/` and `?` any one character but a `/`.
+fn matches(pattern: &str, key: &str) -> bool {
+    match pattern.chars().next() {
+        None => key.is_empty(),
+        Some('*') if pattern.starts_with("**") => {
+            let rest = pattern[2..].trim_start_matches('/');
+            // `**/` also matches nothing at all, so `**/a` matches `a`.
+            (pattern[2..].starts_with('/') && matches(rest, key)) ||
+                key.char_indices().map(|(i, _)| i).chain(Some(key.len()))
+                    .any(|i| matches(&pattern[2..], &key[i..]))
+        }
+        Some('*') => {
+            let end = key.find('/').unwrap_or(key.len());
+            key[..end].char_indices().map(|(i, _)| i).chain(Some(end))
+                .any(|i| matches(&pattern[1..], &key[i..]))
+        }
+        Some('?') => {
+            match key.chars().next() {
+                Some(c) if c != '/' => matches(&pattern[1..], &key[c.len_utf8()..]),
+                _ => false,
+            }
+        }
+        Some(p) => {
+            match key.chars().next() {
+                Some(c) if c == p => matches(&pattern[p.len_utf8()..], &key[c.len_utf8()..]),
+                _ => false,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags_objects_with_their_directories() {
+        let policy = Policy::default();
+        let key = |k| policy.lookup(k).surrogate_key;
+        assert_eq!(key("dist/channel-rust-nightly.toml"), Some("dist".to_string()));
+        assert_eq!(key("dist/2020-01-01/cargo.tar.xz"), Some("dist 2020-01-01".to_string()));
+        assert_eq!(key("doc/nightly/std/index.html"), Some("doc nightly".to_string()));
+        assert_eq!(key("rust/dist/cargo.tar.xz"), Some("rust dist".to_string()));
+        assert_eq!(key("index.html"), None);
+    }
+
+    #[test]
+    fn matches_globs() {
+        assert!(matches("**.toml", "dist/channel-rust-nightly.toml"));
+        assert!(matches("**/channel-rust-*", "channel-rust-nightly.toml"));
+        assert!(matches("**/channel-rust-*", "rust/dist/channel-rust-nightly.toml"));
+        assert!(!matches("**/channel-rust-*", "dist/channel-rust-nightly/x"));
+        assert!(matches("dist/*.asc", "dist/a.asc"));
+        assert!(!matches("dist/*.asc", "dist/2020-01-01/a.asc"));
+        assert!(matches("**/????-??-??/**", "dist/2020-01-01/a.tar.xz"));
+        assert!(!matches("**/????-??-??/**", "dist/2020/01-01/a.tar.xz"));
+        assert!(!matches("**/????-??-??/**", "dist/2020-01-011/a.tar.xz"));
+        assert!(!matches("dist/?", "dist/"));
+        assert!(matches("", ""));
+        assert!(!matches("", "a"));
+    }
+
+    #[test]
+    fn first_matching_rule_wins_per_header() {
+        let policy = Policy::new(false);
+        let manifest = policy.lookup("dist/2020-01-01/channel-rust-nightly.toml");
+        assert_eq!(manifest.cache_control.as_deref(), Some("public, max-age=300"));
+        assert_eq!(manifest.content_type.as_deref(), Some("application/toml"));
+        let tarball = policy.lookup("dist/2020-01-01/cargo-nightly.tar.xz");
+        assert_eq!(tarball.cache_control.as_deref(), Some("public, max-age=31536000, immutable"));
+        assert_eq!(tarball.content_type, None);
+        let docs = policy.lookup("doc/nightly/std/index.html");
+        assert_eq!(docs.cache_control, None);
+        assert_eq!(docs.content_type.as_deref(), Some("text/html; charset=utf-8"));
+
+        let config = "[[metadata]]\npattern = \"doc/**\"\ncache-control = \"no-cache\"\n\
+                      [[metadata]]\npattern = \"**.html\"\ncache-control = \"public\"\n\
+                      content-type = \"text/html\"".parse::<Value>().unwrap();
+        let policy = Policy::from_config(config.get("metadata"));
+        let docs = policy.lookup("doc/nightly/std/index.html");
+        assert_eq!(docs.cache_control.as_deref(), Some("no-cache"));
+        assert_eq!(docs.content_type.as_deref(), Some("text/html"));
+        assert_eq!(policy.lookup("dist/2020-01-01/a.tar.xz").cache_control, None);
+    }
+
+    #[test]
+    fn republished_dated_directories_are_cached_briefly() {
+        let policy = Policy::new(true);
+        let tarball = policy.lookup("dist/2020-01-01/cargo-nightly.tar.xz");
+        assert_eq!(tarball.cache_control.as_deref(), Some("public, max-age=300"));
+    }
+}
@@ -0,0 +1,135 @@
+//! Secondary targets every release is also published to once the primary
+//! storage has it, configured by the `[[dist.mirrors]]` tables in the
+//! secrets.
+//!
+//! Mirrors are best effort: one failing doesn't fail the release, it's just
+//! reported as such.
+
+use std::path::Path;
+
+use toml::Value;
+
+use cdn::{self, Cdn};
+use storage::{self, Storage};
+
+pub struct Mirror {
+    pub name: String,
+    /// Prepended to every key published to the mirror. Either empty or
+    /// ending in a `/`, like `rust/`.
+    pub prefix: String,
+    pub storage: Box<dyn Storage>,
+    pub cdn: Option<Box<dyn Cdn>>,
+}
+
+impl Mirror {
+    /// `key` in the mirror.
+    pub fn key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+}
+
+/// Creates the mirrors in `config`, the `dist.mirrors` array of the secrets.
+///
+/// Like for the primary storage and CDNs, keys missing from a mirror's
+/// `storage` and `cdn` tables are looked up in `defaults` (the `dist` table)
+/// instead, except for the bucket, which a mirror always has to name so it
+/// can't end up publishing to the primary one by accident. `work` is a
+/// scratch directory they may write files to.
+///
+/// A mirror that isn't configured properly is reported and skipped, as it
+/// would be if publishing to it failed.
+pub fn from_config(config: Option<&Value>, defaults: &Value, work: &Path) -> Vec<Mirror> {
+    let config = match config.map(|c| c.as_array()) {
+        Some(Some(config)) => config,
+        Some(None) => {
+            println!("skipping mirrors: dist.mirrors is not an array");
+            return Vec::new()
+        }
+        None => return Vec::new(),
+    };
+    let mut defaults = defaults.clone();
+    if let Some(defaults) = defaults.as_table_mut() {
+        defaults.remove("upload-bucket");
+    }
+    config.iter().enumerate().filter_map(|(i, mirror)| {
+        match from_table(mirror, &defaults, work) {
+            Ok(mirror) => Some(mirror),
+            Err(e) => {
+                let name = mirror.get("name").and_then(|n| n.as_str())
+                    .map(|n| n.to_string())
+                    .unwrap_or_else(|| format!("#{}", i + 1));
+                println!("skipping mirror {}: {}", name, e);
+                None
+            }
+        }
+    }).collect()
+}
+
+fn from_table(mirror: &Value, defaults: &Value, work: &Path) -> Result<Mirror, String> {
+    let name = mirror.get("name")
+        .and_then(|n| n.as_str())
+        .ok_or("no name")?
+        .to_string();
+    let prefix = match mirror.get("prefix") {
+        Some(prefix) => prefix.as_str().ok_or("prefix not a string")?.trim_matches('/'),
+        None => "",
+    };
+    let storage = mirror.get("storage").ok_or("no storage")?;
+    let cdn = match mirror.get("cdn") {
+        Some(cdn) => Some(cdn::from_config(cdn, defaults, work)?),
+        None => None,
+    };
+    Ok(Mirror {
+        prefix: if prefix.is_empty() { String::new() } else { format!("{}/", prefix) },
+        storage: storage::from_config(storage, defaults, work)?,
+        cdn,
+        name,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn skips_broken_mirrors() {
+        let config = r#"
+            upload-bucket = "primary"
+            aws-access-key-id = "key"
+            aws-secret-key = "secret"
+
+            [[mirrors]]
+            name = "local"
+            prefix = "/rust/"
+            storage = { provider = "local", path = "/srv/mirror" }
+            cdn = { provider = "recording", path = "/srv/purges" }
+
+            [[mirrors]]
+            name = "no-bucket"
+            storage = { provider = "s3" }
+
+            [[mirrors]]
+            storage = { provider = "local", path = "/srv/nameless" }
+
+            [[mirrors]]
+            name = "bad-cdn"
+            storage = { provider = "local", path = "/srv/mirror" }
+            cdn = { provider = "akamai" }
+
+            [[mirrors]]
+            name = "s3"
+            storage = { provider = "s3", bucket = "mirror" }
+        "#.parse::<Value>().unwrap();
+        let mirrors = from_config(config.get("mirrors"), &config, &env::temp_dir());
+        let found = mirrors.iter()
+            .map(|m| (&m.name[..], &m.prefix[..], m.cdn.is_some()))
+            .collect::<Vec<_>>();
+        assert_eq!(found, [("local", "rust/", true), ("s3", "", false)]);
+        assert_eq!(mirrors[0].key("dist/"), "rust/dist/");
+
+        assert!(from_config(Some(&Value::Integer(1)), &config, &env::temp_dir()).is_empty());
+        assert!(from_config(None, &config, &env::temp_dir()).is_empty());
+    }
+}
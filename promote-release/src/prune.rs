@@ -0,0 +1,349 @@
+//! Which of the dated release archives under `<upload-dir>/<date>/` are kept
+//! around, configured by the `[dist.retention]` table in the secrets.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use toml::Value;
+
+use verify::Object;
+
+/// A release found in one of the dated directories, through its manifest.
+pub struct Release {
+    pub channel: String,
+    pub date: String,
+    /// Keys of everything the manifest references, including itself.
+    pub keys: BTreeSet<String>,
+}
+
+pub struct Retention {
+    /// Channels whose releases are kept forever.
+    pub keep_channels: Vec<String>,
+    /// How many days the releases of other channels are kept for.
+    pub days: i64,
+    /// Whether the first release of other channels in each month is kept
+    /// forever too.
+    pub monthly: bool,
+}
+
+impl Retention {
+    /// Reads the `[dist.retention]` table `config`. By default stable and
+    /// beta releases are kept forever, and nightlies for 90 days plus the
+    /// first of each month.
+    pub fn from_config(config: Option<&Value>) -> Retention {
+        let get = |key: &str| config.and_then(|c| c.get(key));
+        Retention {
+            keep_channels: match get("keep-channels") {
+                Some(channels) => channels.as_array()
+                    .expect("keep-channels not an array")
+                    .iter()
+                    .map(|c| c.as_str().expect("channel not a string").to_string())
+                    .collect(),
+                None => vec!["stable".to_string(), "beta".to_string()],
+            },
+            days: get("days")
+                .map(|d| d.as_integer().expect("retention days not an integer"))
+                .unwrap_or(90),
+            monthly: get("keep-monthly")
+                .map(|m| m.as_bool().expect("keep-monthly not a bool"))
+                .unwrap_or(true),
+        }
+    }
+
+    /// Picks which of the `dates` (`YYYY-MM-DD`) a `channel` was released on
+    /// to keep as of `today`.
+    pub fn keep(&self, channel: &str, dates: &BTreeSet<String>, today: &str) -> BTreeSet<String> {
+        if self.keep_channels.iter().any(|c| c == channel) {
+            return dates.clone()
+        }
+        let today = days(today).expect("bad date for today");
+        let mut keep = BTreeSet::new();
+        let mut months = BTreeSet::new();
+        // `dates` is sorted, so the first one seen in a month is its first.
+        for date in dates {
+            let first_of_month = months.insert(&date[..7]);
+            let recent = days(date).is_some_and(|d| today - d < self.days);
+            if recent || (self.monthly && first_of_month) {
+                keep.insert(date.clone());
+            }
+        }
+        keep
+    }
+
+    /// Picks the objects in the dated directories `dirs`, by date, that are
+    /// past retention as of `today`.
+    ///
+    /// `releases` are the manifests found in those directories, and anything
+    /// referenced by a kept one is kept too, as is everything in `protected`.
+    /// Directories without any manifests are left alone.
+    pub fn expired<'a>(&self, dirs: &'a BTreeMap<String, Vec<Object>>, releases: &[Release],
+                       protected: &BTreeSet<String>, today: &str) -> Vec<&'a Object> {
+        let mut dates = BTreeMap::new();
+        for release in releases {
+            dates.entry(&release.channel[..]).or_insert_with(BTreeSet::new)
+                .insert(release.date.clone());
+        }
+        let mut kept = BTreeSet::new();
+        for (channel, dates) in dates {
+            for date in self.keep(channel, &dates, today) {
+                kept.insert((channel, date));
+            }
+        }
+        let is_kept = |r: &Release| kept.contains(&(&r.channel[..], r.date.clone()));
+
+        let mut protected = protected.clone();
+        for release in releases.iter().filter(|r| is_kept(r)) {
+            protected.extend(release.keys.iter().cloned());
+        }
+
+        let mut expired = Vec::new();
+        for (date, objects) in dirs {
+            let releases = releases.iter().filter(|r| r.date == *date).collect::<Vec<_>>();
+            if releases.is_empty() {
+                println!("no manifests in the {} directory, leaving it alone", date);
+                continue
+            }
+            let all_expired = releases.iter().all(|r| !is_kept(r));
+            let expired_keys = releases.iter()
+                .filter(|r| !is_kept(r))
+                .flat_map(|r| r.keys.iter())
+                .collect::<BTreeSet<_>>();
+            for object in objects {
+                // Signatures and hashes go along with whatever they're of.
+                let base = object.key.trim_end_matches(".asc").trim_end_matches(".sha256");
+                if protected.contains(base) {
+                    continue
+                }
+                if all_expired || expired_keys.contains(&base.to_string()) {
+                    expired.push(object);
+                }
+            }
+        }
+        expired.sort_by(|a, b| a.key.cmp(&b.key));
+        expired
+    }
+}
+
+/// The channel a manifest named `name` was released on, if it's one.
+///
+/// That's `$channel` for `channel-rust-$channel.toml`, except for the copies
+/// of stable manifests named after the version, like
+/// `channel-rust-1.40.0.toml` and `channel-rust-1.40.toml`, which count as
+/// stable releases.
+pub fn manifest_channel(name: &str) -> Option<String> {
+    let channel = name.strip_prefix("channel-rust-")?.strip_suffix(".toml")?;
+    if channel.starts_with(|c: char| c.is_ascii_digit()) {
+        let channel = if channel.contains("beta") { "beta" } else { "stable" };
+        return Some(channel.to_string())
+    }
+    Some(channel.to_string())
+}
+
+/// The key of the object a manifest refers to at `url`, which must be under
+/// `upload_dir`.
+///
+/// Only the path of `url` is looked at, so it doesn't matter which host
+/// serves it or over what. If `addr`, the `upload-addr` the manifest was
+/// written with, has a path of its own that's stripped too.
+pub fn url_key(url: &str, addr: &str, upload_dir: &str) -> Option<String> {
+    let path = |url: &str| {
+        let rest = url.split_once("://").map(|(_, rest)| rest).unwrap_or(url);
+        rest.split_once('/').map(|(_, path)| path.trim_matches('/').to_string())
+            .unwrap_or_default()
+    };
+    let url_path = path(url);
+    let addr_path = path(addr);
+    let key = if addr_path.is_empty() {
+        &url_path[..]
+    } else {
+        url_path.strip_prefix(&addr_path[..])?.strip_prefix('/')?
+    };
+    if key.strip_prefix(upload_dir)?.starts_with('/') {
+        Some(key.to_string())
+    } else {
+        None
+    }
+}
+
+/// Whether `s` looks like the name of a dated directory.
+pub fn is_date(s: &str) -> bool {
+    days(s).is_some()
+}
+
+/// Converts a `YYYY-MM-DD` date to a number of days since 1970-01-01.
+fn days(date: &str) -> Option<i64> {
+    let mut parts = date.splitn(3, '-');
+    let year = parts.next().filter(|y| y.len() == 4)?.parse::<i64>().ok()?;
+    let month = parts.next().filter(|m| m.len() == 2)?.parse::<i64>().ok()?;
+    let day = parts.next().filter(|d| d.len() == 2)?.parse::<i64>().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None
+    }
+    // Howard Hinnant's `days_from_civil`.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    Some(era * 146097 + day_of_era - 719468)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn retention() -> Retention {
+        Retention::from_config(None)
+    }
+
+    fn dates(dates: &[&str]) -> BTreeSet<String> {
+        dates.iter().map(|d| d.to_string()).collect()
+    }
+
+    #[test]
+    fn days_since_epoch() {
+        assert_eq!(days("1970-01-01"), Some(0));
+        assert_eq!(days("1970-02-01"), Some(31));
+        assert_eq!(days("2000-03-01"), Some(11017));
+        assert_eq!(days("1969-12-31"), Some(-1));
+        assert_eq!(days("2020-13-01"), None);
+        assert_eq!(days("2020-1-01"), None);
+        assert_eq!(days("nightly"), None);
+        assert!(is_date("2020-01-01"));
+        assert!(!is_date("1.40.0"));
+    }
+
+    #[test]
+    fn keeps_configured_channels_forever() {
+        let all = dates(&["2015-05-15", "2019-12-19"]);
+        assert_eq!(retention().keep("stable", &all, "2024-01-01"), all);
+        assert_eq!(retention().keep("beta", &all, "2024-01-01"), all);
+    }
+
+    #[test]
+    fn keeps_recent_and_monthly_nightlies() {
+        let all = dates(&["2023-08-01", "2023-08-02", "2023-09-02", "2023-09-03",
+                          "2023-12-30", "2023-12-31"]);
+        let kept = retention().keep("nightly", &all, "2024-01-01");
+        assert_eq!(kept, dates(&["2023-08-01", "2023-09-02", "2023-12-30", "2023-12-31"]));
+
+        let mut no_monthly = retention();
+        no_monthly.monthly = false;
+        no_monthly.days = 2;
+        assert_eq!(no_monthly.keep("nightly", &all, "2024-01-01"), dates(&["2023-12-31"]));
+    }
+
+    #[test]
+    fn versioned_manifests_are_stable() {
+        let channel = |name| manifest_channel(name);
+        assert_eq!(channel("channel-rust-nightly.toml"), Some("nightly".to_string()));
+        assert_eq!(channel("channel-rust-1.40.0.toml"), Some("stable".to_string()));
+        assert_eq!(channel("channel-rust-1.40.toml"), Some("stable".to_string()));
+        assert_eq!(channel("channel-rust-1.41.0-beta.toml"), Some("beta".to_string()));
+        assert_eq!(channel("channel-rust-nightly.toml.asc"), None);
+        assert_eq!(channel("rustc-nightly-x86_64-unknown-linux-gnu.tar.xz"), None);
+    }
+
+    #[test]
+    fn maps_urls_to_keys() {
+        let key = |url, addr| url_key(url, addr, "dist");
+        let expected = Some("dist/2020-01-01/cargo.tar.xz".to_string());
+        let url = "https://static.rust-lang.org/dist/2020-01-01/cargo.tar.xz";
+        assert_eq!(key(url, "https://static.rust-lang.org"), expected);
+        assert_eq!(key(url, "https://static.rust-lang.org/"), expected);
+        assert_eq!(key(url, "http://mirror.example.com"), expected);
+        assert_eq!(key("https://example.com/rust/dist/2020-01-01/cargo.tar.xz",
+                       "https://example.com/rust"), expected);
+        assert_eq!(key("https://example.com/other/2020-01-01/cargo.tar.xz",
+                       "https://example.com"), None);
+        assert_eq!(key("https://example.com/distx/cargo.tar.xz", "https://example.com"), None);
+        assert_eq!(key(url, "https://example.com/rust"), None);
+    }
+
+    fn object(key: &str) -> Object {
+        Object { key: key.to_string(), size: 1, etag: String::new() }
+    }
+
+    fn release(channel: &str, date: &str, keys: &[&str]) -> Release {
+        Release {
+            channel: channel.to_string(),
+            date: date.to_string(),
+            keys: keys.iter().map(|k| k.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn expires_only_old_nightlies() {
+        let mut dirs = BTreeMap::new();
+        let mut releases = Vec::new();
+        // A stable and a nightly released the same day, long ago.
+        dirs.insert("2019-12-18".to_string(), vec![
+            object("dist/2019-12-18/channel-rust-nightly.toml"),
+            object("dist/2019-12-18/channel-rust-nightly.toml.asc"),
+            object("dist/2019-12-18/rustc-nightly.tar.xz"),
+            object("dist/2019-12-18/rustc-nightly.tar.xz.sha256"),
+            object("dist/2019-12-18/cargo-shared.tar.xz"),
+            object("dist/2019-12-18/channel-rust-stable.toml"),
+            object("dist/2019-12-18/channel-rust-1.40.0.toml"),
+            object("dist/2019-12-18/channel-rust-1.40.0.toml.asc"),
+            object("dist/2019-12-18/channel-rust-1.40.toml"),
+            object("dist/2019-12-18/rustc-1.40.0.tar.xz"),
+        ]);
+        releases.push(release("nightly", "2019-12-18", &[
+            "dist/2019-12-18/channel-rust-nightly.toml",
+            "dist/2019-12-18/rustc-nightly.tar.xz",
+            "dist/2019-12-18/cargo-shared.tar.xz",
+        ]));
+        for name in &["channel-rust-stable.toml", "channel-rust-1.40.0.toml",
+                      "channel-rust-1.40.toml"] {
+            let manifest = format!("dist/2019-12-18/{}", name);
+            let channel = manifest_channel(name).unwrap();
+            releases.push(release(&channel, "2019-12-18", &[
+                &manifest,
+                "dist/2019-12-18/rustc-1.40.0.tar.xz",
+                "dist/2019-12-18/cargo-shared.tar.xz",
+            ]));
+        }
+        // An old beta, an old nightly alone in its directory, and a recent
+        // nightly still referenced by the live manifest.
+        dirs.insert("2019-12-20".to_string(), vec![
+            object("dist/2019-12-20/channel-rust-beta.toml"),
+            object("dist/2019-12-20/rustc-beta.tar.xz"),
+        ]);
+        releases.push(release("beta", "2019-12-20", &[
+            "dist/2019-12-20/channel-rust-beta.toml",
+            "dist/2019-12-20/rustc-beta.tar.xz",
+        ]));
+        dirs.insert("2019-12-21".to_string(), vec![
+            object("dist/2019-12-21/channel-rust-nightly.toml"),
+            object("dist/2019-12-21/rustc-nightly.tar.xz"),
+            object("dist/2019-12-21/rustc-nightly.tar.xz.asc"),
+            object("dist/2019-12-21/unreferenced.txt"),
+            object("dist/2019-12-21/live.tar.xz"),
+        ]);
+        releases.push(release("nightly", "2019-12-21", &[
+            "dist/2019-12-21/channel-rust-nightly.toml",
+            "dist/2019-12-21/rustc-nightly.tar.xz",
+            "dist/2019-12-21/live.tar.xz",
+        ]));
+        // No manifests at all.
+        dirs.insert("2019-12-22".to_string(), vec![object("dist/2019-12-22/stray.tar.xz")]);
+
+        let protected = ["dist/2019-12-21/live.tar.xz".to_string()].iter().cloned().collect();
+        let mut retention = retention();
+        retention.monthly = false;
+        let expired = retention.expired(&dirs, &releases, &protected, "2020-06-01")
+            .into_iter()
+            .map(|o| &o.key[..])
+            .collect::<Vec<_>>();
+        assert_eq!(expired, [
+            "dist/2019-12-18/channel-rust-nightly.toml",
+            "dist/2019-12-18/channel-rust-nightly.toml.asc",
+            "dist/2019-12-18/rustc-nightly.tar.xz",
+            "dist/2019-12-18/rustc-nightly.tar.xz.sha256",
+            "dist/2019-12-21/channel-rust-nightly.toml",
+            "dist/2019-12-21/rustc-nightly.tar.xz",
+            "dist/2019-12-21/rustc-nightly.tar.xz.asc",
+            "dist/2019-12-21/unreferenced.txt",
+        ]);
+    }
+}
@@ -0,0 +1,123 @@
+//! Extraction of a single release's notes from rust-lang/rust's
+//! `RELEASES.md`.
+
+use std::collections::BTreeMap;
+
+use pulldown_cmark::{html, Parser};
+
+/// Pulls the section for `version` out of the contents of `RELEASES.md`,
+/// including its heading.
+///
+/// Sections start with a setext heading like:
+///
+/// ```text
+/// Version 1.41.0 (2020-01-30)
+/// ===========================
+/// ```
+///
+/// and run until the next such heading. Returns `None` if there's no section
+/// for `version`.
+pub fn extract(releases: &str, version: &str) -> Option<String> {
+    let lines = releases.lines().collect::<Vec<_>>();
+    let is_heading = |i: usize| {
+        lines[i].starts_with("Version ") &&
+            lines.get(i + 1).is_some_and(|l| !l.is_empty() && l.chars().all(|c| c == '='))
+    };
+    let start = (0..lines.len()).find(|&i| {
+        is_heading(i) && lines[i]["Version ".len()..].split(' ').next() == Some(version)
+    })?;
+    let end = (start + 1..lines.len()).find(|&i| is_heading(i)).unwrap_or(lines.len());
+
+    let mut section = lines[start..end].join("\n");
+    let trimmed = section.trim_end().len();
+    section.truncate(trimmed);
+    section.push('\n');
+    Some(section)
+}
+
+/// Maps every version with a section in `RELEASES.md` to the release date in
+/// its `Version 1.41.0 (2020-01-30)` heading.
+pub fn release_dates(releases: &str) -> BTreeMap<String, String> {
+    releases.lines().filter_map(|line| {
+        let mut parts = line.strip_prefix("Version ")?.split(' ');
+        let version = parts.next()?;
+        let date = parts.next()?.strip_prefix('(')?.strip_suffix(')')?;
+        Some((version.to_string(), date.to_string()))
+    }).collect()
+}
+
+/// Renders the Markdown `notes` for `version` as a standalone HTML page.
+pub fn to_html(version: &str, notes: &str) -> String {
+    let mut body = String::new();
+    html::push_html(&mut body, Parser::new(notes));
+    format!("<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<title>Rust {} release notes</title>
+</head>
+<body>
+{}</body>
+</html>
+", version, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RELEASES: &str = "\
+Version 1.41.0 (2020-01-30)
+===========================
+
+Language
+--------
+- Something new.
+
+Version 1.40.0 (2019-12-19)
+===========================
+
+- Something old.
+  Version 1.39.0 isn't a heading when it's not underlined.
+
+";
+
+    #[test]
+    fn extracts_sections() {
+        assert_eq!(extract(RELEASES, "1.41.0").unwrap(), "\
+Version 1.41.0 (2020-01-30)
+===========================
+
+Language
+--------
+- Something new.
+");
+    }
+
+    #[test]
+    fn extracts_the_last_section() {
+        assert_eq!(extract(RELEASES, "1.40.0").unwrap(), "\
+Version 1.40.0 (2019-12-19)
+===========================
+
+- Something old.
+  Version 1.39.0 isn't a heading when it's not underlined.
+");
+    }
+
+    #[test]
+    fn misses_unreleased_versions() {
+        assert_eq!(extract(RELEASES, "1.42.0"), None);
+        assert_eq!(extract(RELEASES, "1.39.0"), None);
+        assert_eq!(extract(RELEASES, "1.41"), None);
+        assert_eq!(extract("", "1.41.0"), None);
+    }
+
+    #[test]
+    fn reads_release_dates() {
+        let dates = release_dates(RELEASES);
+        assert_eq!(dates.len(), 2);
+        assert_eq!(dates["1.41.0"], "2020-01-30");
+        assert_eq!(dates["1.40.0"], "2019-12-19");
+    }
+}
@@ -0,0 +1,310 @@
+//! A smoke test of a published channel manifest, installing the toolchain it
+//! describes the same way rustup would and checking that `rustc` runs.
+
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+use std::process::Command;
+
+use flate2::read::GzDecoder;
+use tar::Archive;
+use xz2::read::XzDecoder;
+
+use gpg::Verifier;
+use hashes;
+use http::Client;
+
+pub type Result<T> = ::std::result::Result<T, Box<dyn Error>>;
+
+/// Components installed when a manifest doesn't have any profiles yet.
+const FALLBACK_PROFILE: &[&str] = &["rustc", "cargo", "rust-std"];
+
+/// Components the toolchain is no use without. Nightlies are released even
+/// when others in the profile like rustfmt or clippy are missing, so those are
+/// skipped when the manifest says they're unavailable.
+const REQUIRED: &[&str] = &["rustc", "cargo", "rust-std"];
+
+/// Installs the `default` profile of the manifest at `manifest_url` for
+/// `target` into a fresh prefix under `dir`, verifying the hash and
+/// signature of everything downloaded along the way, and returns the output
+/// of the installed `rustc --version`.
+pub fn run(manifest_url: &str,
+           target: &str,
+           dir: &Path,
+           verifier: &Verifier,
+           http: &mut Client)
+    -> Result<String>
+{
+    drop(fs::remove_dir_all(dir));
+    fs::create_dir_all(dir)?;
+
+    let manifest_path = dir.join("channel-rust.toml");
+    http.download(manifest_url, &manifest_path)?;
+    http.download(&format!("{}.asc", manifest_url), &dir.join("channel-rust.toml.asc"))?;
+    if !verifier.verify(&manifest_path, &dir.join("channel-rust.toml.asc"))? {
+        return Err(format!("bad signature for {}", manifest_url).into())
+    }
+    let manifest: toml::Value = fs::read_to_string(&manifest_path)?.parse()?;
+
+    let prefix = dir.join("prefix");
+    for name in components(&manifest)? {
+        let (url, hash) = match package(&manifest, &name, target)? {
+            Some(package) => package,
+            None if REQUIRED.contains(&&name[..]) => {
+                return Err(format!("{} isn't available for {}", name, target).into())
+            }
+            None => {
+                println!("skipping {}, which isn't available for {}", name, target);
+                continue
+            }
+        };
+        println!("installing {} from {}", name, url);
+        let file_name = url.rsplit('/').next().unwrap();
+        let tarball = dir.join(file_name);
+        let signature = dir.join(format!("{}.asc", file_name));
+        http.download(&url, &tarball)?;
+        http.download(&format!("{}.asc", url), &signature)?;
+
+        let actual = hashes::sha256_file(&tarball)?;
+        if actual != hash {
+            return Err(format!("{} has hash {}, manifest says {}", url, actual, hash).into())
+        }
+        if !verifier.verify(&tarball, &signature)? {
+            return Err(format!("bad signature for {}", url).into())
+        }
+        install(&tarball, &dir.join("unpack"), &prefix)?;
+        fs::remove_file(&tarball)?;
+    }
+
+    let output = Command::new(prefix.join("bin/rustc")).arg("--version").output()?;
+    if !output.status.success() {
+        return Err(format!("rustc --version failed: {}\n{}", output.status,
+                           String::from_utf8_lossy(&output.stderr)).into())
+    }
+    Ok(String::from_utf8(output.stdout)?.trim().to_string())
+}
+
+/// Names of the packages making up the `default` profile, with renames
+/// applied.
+fn components(manifest: &toml::Value) -> Result<Vec<String>> {
+    let profile = match manifest.get("profiles").and_then(|p| p.get("default")) {
+        Some(profile) => {
+            profile.as_array()
+                .ok_or("default profile isn't an array")?
+                .iter()
+                .map(|c| c.as_str().map(|s| s.to_string()).ok_or("component isn't a string"))
+                .collect::<::std::result::Result<Vec<_>, _>>()?
+        }
+        None => FALLBACK_PROFILE.iter().map(|s| s.to_string()).collect(),
+    };
+    Ok(profile.into_iter().map(|name| {
+        let renamed = manifest.get("renames")
+            .and_then(|r| r.get(&name))
+            .and_then(|r| r.get("to"))
+            .and_then(|to| to.as_str());
+        match renamed {
+            Some(to) => to.to_string(),
+            None => name,
+        }
+    }).collect())
+}
+
+/// Looks up the URL and SHA-256 of the `name` package for `target`,
+/// preferring the xz tarball. Returns `None` if the manifest doesn't have it
+/// or marks it unavailable.
+fn package(manifest: &toml::Value, name: &str, target: &str)
+    -> Result<Option<(String, String)>>
+{
+    let targets = match manifest.get("pkg").and_then(|p| p.get(name)).and_then(|p| p.get("target")) {
+        Some(targets) => targets,
+        None => return Ok(None),
+    };
+    // Target-independent packages like `rust-src` are listed under `*`.
+    let pkg = match targets.get(target).or_else(|| targets.get("*")) {
+        Some(pkg) => pkg,
+        None => return Ok(None),
+    };
+    if pkg.get("available").and_then(|a| a.as_bool()) != Some(true) {
+        return Ok(None)
+    }
+    for &(url, hash) in &[("xz_url", "xz_hash"), ("url", "hash")] {
+        if let (Some(url), Some(hash)) = (pkg.get(url).and_then(|u| u.as_str()),
+                                          pkg.get(hash).and_then(|h| h.as_str())) {
+            return Ok(Some((url.to_string(), hash.to_string())))
+        }
+    }
+    Err(format!("no url for {} for {}", name, target).into())
+}
+
+/// Installs the components in the installer `tarball` into `prefix`, using
+/// `unpack` as scratch space.
+///
+/// Like rustup, this doesn't run `install.sh` but copies over everything each
+/// component's `manifest.in` lists.
+fn install(tarball: &Path, unpack: &Path, prefix: &Path) -> Result<()> {
+    drop(fs::remove_dir_all(unpack));
+    fs::create_dir_all(unpack)?;
+    let file = File::open(tarball)?;
+    let reader: Box<dyn Read> = if tarball.extension().is_some_and(|e| e == "xz") {
+        Box::new(XzDecoder::new(file))
+    } else {
+        Box::new(GzDecoder::new(file))
+    };
+    Archive::new(reader).unpack(unpack)?;
+
+    let root = match fs::read_dir(unpack)?.next() {
+        Some(entry) => entry?.path(),
+        None => return Err(format!("{} is empty", tarball.display()).into()),
+    };
+    for component in fs::read_to_string(root.join("components"))?.lines() {
+        let component_dir = root.join(component);
+        for line in fs::read_to_string(component_dir.join("manifest.in"))?.lines() {
+            let path = match line.split_once(':') {
+                Some(("file", path)) | Some(("dir", path)) => path,
+                _ => return Err(format!("bad line in {}/manifest.in: {}", component, line).into()),
+            };
+            copy(&component_dir.join(path), &prefix.join(path))?;
+        }
+    }
+    Ok(())
+}
+
+fn copy(src: &Path, dst: &Path) -> Result<()> {
+    if src.is_dir() {
+        fs::create_dir_all(dst)?;
+        for entry in fs::read_dir(src)? {
+            let entry = entry?;
+            copy(&entry.path(), &dst.join(entry.file_name()))?;
+        }
+    } else {
+        fs::create_dir_all(dst.parent().unwrap())?;
+        fs::copy(src, dst)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::env;
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::{Arc, Mutex};
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use tar::{Builder, Header};
+
+    use super::*;
+    use gpg::tests::{keygen, sign};
+    use test_server::{Reply, Server};
+
+    const TARGET: &str = "x86_64-unknown-linux-gnu";
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("smoke-{}-{}", name, process::id()));
+        drop(fs::remove_dir_all(&dir));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// An installer tarball with the single `component`, which consists of
+    /// the executable `file` with `contents`.
+    fn installer(component: &str, file: &str, contents: &str) -> Vec<u8> {
+        let mut builder = Builder::new(GzEncoder::new(Vec::new(), Compression::fast()));
+        let root = format!("{}-nightly-{}", component, TARGET);
+        let mut add = |path: String, contents: &[u8], mode| {
+            let mut header = Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(mode);
+            header.set_cksum();
+            builder.append_data(&mut header, path, contents).unwrap();
+        };
+        add(format!("{}/components", root), format!("{}\n", component).as_bytes(), 0o644);
+        add(format!("{}/{}/manifest.in", root, component), format!("file:{}\n", file).as_bytes(),
+            0o644);
+        add(format!("{}/{}/{}", root, component, file), contents.as_bytes(), 0o755);
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    /// Serves a signed manifest whose default profile has rustc, cargo,
+    /// rust-std and rustfmt, where the ones in `unavailable` are marked as
+    /// such. Returns the server and the manifest's URL.
+    fn serve_release(dir: &Path, home: &Path, unavailable: &[&str]) -> (Server, String) {
+        let files = Arc::new(Mutex::new(HashMap::new()));
+        let served = files.clone();
+        let server = Server::new(move |request| {
+            match served.lock().unwrap().get(&request.path) {
+                Some(body) => Reply::Status(200, Clone::clone(body)),
+                None => Reply::Status(404, Vec::new()),
+            }
+        });
+        let mut files = files.lock().unwrap();
+        let mut add = |path: String, body: Vec<u8>| {
+            let signature = sign(home, &body, dir);
+            files.insert(format!("{}.asc", path), signature);
+            files.insert(path, body);
+        };
+
+        let mut manifest = String::from(
+            "[profiles]\ndefault = [\"rustc\", \"cargo\", \"rust-std\", \"rustfmt\"]\n");
+        for &(component, file, contents) in &[
+            ("rustc", "bin/rustc", "#!/bin/sh\necho rustc 1.0.0-smoke\n"),
+            ("cargo", "bin/cargo", "#!/bin/sh\n"),
+            ("rust-std", "lib/libstd.rlib", ""),
+            ("rustfmt", "bin/rustfmt", "#!/bin/sh\n"),
+        ] {
+            manifest.push_str(&format!("[pkg.{}.target.{}]\n", component, TARGET));
+            if unavailable.contains(&component) {
+                manifest.push_str("available = false\n");
+                continue
+            }
+            let tarball = installer(component, file, contents);
+            let path = format!("/dist/{}-nightly-{}.tar.gz", component, TARGET);
+            manifest.push_str(&format!("available = true\nurl = \"{}{}\"\nhash = \"{}\"\n",
+                                       server.url, path, hashes::sha256(&tarball)));
+            add(path, tarball);
+        }
+        add("/dist/channel-rust-nightly.toml".to_string(), manifest.into_bytes());
+        drop(files);
+        let url = format!("{}/dist/channel-rust-nightly.toml", server.url);
+        (server, url)
+    }
+
+    fn client() -> Client {
+        Client::new(&toml::Value::Table(Default::default()))
+    }
+
+    #[test]
+    fn installs_the_default_profile() {
+        let dir = scratch("install");
+        let (home, verifier) = keygen(&dir);
+        let (server, url) = serve_release(&dir, &home, &["rustfmt"]);
+        let version = run(&url, TARGET, &dir.join("smoke"), &verifier, &mut client()).unwrap();
+        assert_eq!(version, "rustc 1.0.0-smoke");
+        assert!(server.requests().iter().all(|r| !r.path.contains("rustfmt")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fails_without_a_required_component() {
+        let dir = scratch("required");
+        let (home, verifier) = keygen(&dir);
+        let (_server, url) = serve_release(&dir, &home, &["cargo"]);
+        let err = run(&url, TARGET, &dir.join("smoke"), &verifier, &mut client()).unwrap_err();
+        assert_eq!(err.to_string(), format!("cargo isn't available for {}", TARGET));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_a_bad_signature() {
+        let dir = scratch("signature");
+        let (home, _) = keygen(&dir);
+        let (_, other) = keygen(&dir.join("other"));
+        let (_server, url) = serve_release(&dir, &home, &[]);
+        let err = run(&url, TARGET, &dir.join("smoke"), &other, &mut client()).unwrap_err();
+        assert_eq!(err.to_string(), format!("bad signature for {}", url));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
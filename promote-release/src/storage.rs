@@ -0,0 +1,555 @@
+//! The storage releases are published to.
+//!
+//! That's the S3 bucket in `dist.upload-bucket` unless a `[dist.storage]`
+//! table in the secrets says otherwise, whose `provider` key selects one of
+//! the implementations below.
+//!
+//! Keys never start with a `/`, and prefixes always end with one, like
+//! `dist/2020-01-01/`.
+//!
+//! Everything uploaded gets the headers the `metadata::Policy` configured for
+//! its key, where the storage supports headers at all.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use toml::Value;
+
+use hashes;
+use metadata::{Metadata, Policy};
+use verify::{self, Object};
+
+pub type Result<T> = ::std::result::Result<T, Box<dyn Error>>;
+
+pub trait Storage {
+    /// Uploads every file under `src` to the same path under `prefix`.
+    fn upload_dir(&self, src: &Path, prefix: &str) -> Result<()> {
+        self.upload_files(src, &hashes::files(src)?, prefix)
+    }
+
+    /// Uploads the files at the relative `paths` in `src` to the same paths
+    /// under `prefix`.
+    fn upload_files(&self, src: &Path, paths: &[String], prefix: &str) -> Result<()>;
+
+    /// Uploads the file `src` as `key`.
+    fn upload_file(&self, src: &Path, key: &str) -> Result<()>;
+
+    /// Downloads `key` to `dst`, returning `false` if there's no such object.
+    fn download_file(&self, key: &str, dst: &Path) -> Result<bool>;
+
+    /// Deletes `keys`, which don't all have to exist.
+    fn delete(&self, keys: &[String]) -> Result<()>;
+
+    /// Makes `prefix` contain exactly the files under `src`, returning the
+    /// keys relative to `prefix` that were uploaded or deleted.
+    fn sync_dir(&self, src: &Path, prefix: &str) -> Result<Vec<String>>;
+
+    /// Lists the objects directly under `prefix`.
+    fn list(&self, prefix: &str) -> Result<Vec<Object>>;
+
+    /// Lists the names of the directories directly under `prefix`.
+    fn list_dirs(&self, prefix: &str) -> Result<Vec<String>>;
+}
+
+/// Creates the storage configured by the `[dist.storage]` table `config`.
+///
+/// Keys missing from `config` are looked up in `defaults` instead, which is
+/// how S3 picks up the bucket and AWS credentials in `[dist]`. `work` is a
+/// scratch directory implementations may write files to.
+pub fn from_config(config: &Value, defaults: &Value, work: &Path)
+    -> ::std::result::Result<Box<dyn Storage>, String>
+{
+    let get = |key: &str, default_key: &str| {
+        let value = config.get(key).or_else(|| defaults.get(default_key));
+        match value.and_then(|v| v.as_str()) {
+            Some(value) => Ok(value.to_string()),
+            None => Err(format!("missing string `{}` in storage config", key)),
+        }
+    };
+    Ok(match &get("provider", "provider")?[..] {
+        "s3" => Box::new(S3 {
+            bucket: get("bucket", "upload-bucket")?,
+            access_key: get("aws-access-key-id", "aws-access-key-id")?,
+            secret_key: get("aws-secret-key", "aws-secret-key")?,
+            work: work.to_path_buf(),
+            policy: Policy::from_config(config.get("metadata").or_else(|| defaults.get("metadata"))),
+        }),
+        "local" => Box::new(Local {
+            root: PathBuf::from(get("path", "path")?),
+        }),
+        other => return Err(format!("unknown storage provider: {}", other)),
+    })
+}
+
+/// An S3 bucket, accessed through the `aws` CLI.
+pub struct S3 {
+    pub bucket: String,
+    pub access_key: String,
+    pub secret_key: String,
+    pub work: PathBuf,
+    pub policy: Policy,
+}
+
+impl S3 {
+    fn aws(&self) -> Command {
+        let mut cmd = Command::new("aws");
+        cmd.env("AWS_ACCESS_KEY_ID", &self.access_key)
+           .env("AWS_SECRET_ACCESS_KEY", &self.secret_key);
+        cmd
+    }
+
+    fn url(&self, key: &str) -> String {
+        format!("s3://{}/{}", self.bucket, key)
+    }
+
+    /// `aws s3 cp` with the flags setting `metadata`.
+    fn cp(&self, metadata: &Metadata) -> Command {
+        let mut cmd = self.aws();
+        cmd.arg("s3")
+           .arg("cp")
+           .arg("--only-show-errors");
+        if let Some(ref cache_control) = metadata.cache_control {
+            cmd.arg("--cache-control").arg(cache_control);
+        }
+        if let Some(ref content_type) = metadata.content_type {
+            cmd.arg("--content-type").arg(content_type);
+        }
+        // S3 can't serve a `Surrogate-Key` header itself, so this comes back
+        // as `x-amz-meta-surrogate-key` for the CDN to pick up.
+        if let Some(ref surrogate_key) = metadata.surrogate_key {
+            cmd.arg("--metadata").arg(format!("surrogate-key={}", surrogate_key));
+        }
+        cmd
+    }
+}
+
+fn run(cmd: &mut Command) -> Result<()> {
+    println!("running {:?}", cmd);
+    let status = cmd.status()?;
+    if !status.success() {
+        return Err(format!("failed command: {:?}: {}", cmd, status).into())
+    }
+    Ok(())
+}
+
+fn output(cmd: &mut Command) -> Result<String> {
+    println!("running {:?}", cmd);
+    let output = cmd.output()?;
+    if !output.status.success() {
+        return Err(format!("failed command: {:?}: {}\n{}", cmd, output.status,
+                           String::from_utf8_lossy(&output.stderr)).into())
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+impl Storage for S3 {
+    fn upload_files(&self, src: &Path, paths: &[String], prefix: &str) -> Result<()> {
+        // A single `aws s3 cp` can only set one set of headers, so the files
+        // are grouped by their metadata, and each group is staged in its own
+        // directory to go up with one recursive copy.
+        let mut groups = BTreeMap::new();
+        for path in paths {
+            let metadata = self.policy.lookup(&format!("{}{}", prefix, path));
+            groups.entry(metadata).or_insert_with(Vec::new).push(path);
+        }
+        let staging = self.work.join("upload");
+        for (metadata, paths) in groups {
+            drop(fs::remove_dir_all(&staging));
+            for path in paths {
+                let staged = staging.join(path);
+                fs::create_dir_all(staged.parent().unwrap())?;
+                if fs::hard_link(src.join(path), &staged).is_err() {
+                    fs::copy(src.join(path), &staged)?;
+                }
+            }
+            run(self.cp(&metadata)
+                    .arg("--recursive")
+                    .arg(format!("{}/", staging.display()))
+                    .arg(self.url(prefix)))?;
+        }
+        drop(fs::remove_dir_all(&staging));
+        Ok(())
+    }
+
+    fn upload_file(&self, src: &Path, key: &str) -> Result<()> {
+        run(self.cp(&self.policy.lookup(key)).arg(src).arg(self.url(key)))
+    }
+
+    fn download_file(&self, key: &str, dst: &Path) -> Result<bool> {
+        drop(fs::remove_file(dst));
+        let status = self.aws()
+            .arg("s3")
+            .arg("cp")
+            .arg("--only-show-errors")
+            .arg(self.url(key))
+            .arg(dst)
+            .status()?;
+        Ok(status.success())
+    }
+
+    fn delete(&self, keys: &[String]) -> Result<()> {
+        // `delete-objects` takes at most 1000 keys at a time.
+        for batch in keys.chunks(1000) {
+            let objects = batch.iter()
+                .map(|key| json!({ "Key": key }))
+                .collect::<Vec<_>>();
+            let json = json!({ "Objects": objects, "Quiet": true }).to_string();
+            let dst = self.work.join("delete.json");
+            File::create(&dst)?.write_all(json.as_bytes())?;
+            run(self.aws()
+                    .arg("s3api")
+                    .arg("delete-objects")
+                    .arg("--bucket").arg(&self.bucket)
+                    .arg("--delete").arg(format!("file://{}", dst.display())))?;
+        }
+        Ok(())
+    }
+
+    fn sync_dir(&self, src: &Path, prefix: &str) -> Result<Vec<String>> {
+        // `aws s3 sync` can't set headers per file either, so it's only asked
+        // what needs doing. It prints a line for every object it would touch,
+        // like:
+        //
+        //   (dryrun) upload: docs/std/index.html to s3://bucket/doc/nightly/std/index.html
+        //   (dryrun) delete: s3://bucket/doc/nightly/std/old.html
+        let dst = self.url(prefix);
+        let out = output(self.aws()
+                             .arg("s3")
+                             .arg("sync")
+                             .arg("--dryrun")
+                             .arg("--delete")
+                             .arg("--no-progress")
+                             .arg(format!("{}/", src.display()))
+                             .arg(&dst))?;
+        let mut uploads = Vec::new();
+        let mut deletes = Vec::new();
+        for line in out.lines() {
+            let line = line.trim_start_matches("(dryrun) ");
+            let (url, list) = if line.starts_with("upload: ") {
+                match line.rsplit(" to ").next() {
+                    Some(url) => (url, &mut uploads),
+                    None => continue,
+                }
+            } else {
+                match line.strip_prefix("delete: ") {
+                    Some(url) => (url, &mut deletes),
+                    None => continue,
+                }
+            };
+            if let Some(key) = url.strip_prefix(&dst[..]) {
+                list.push(key.to_string());
+            }
+        }
+        self.upload_files(src, &uploads, prefix)?;
+        self.delete(&deletes.iter().map(|key| format!("{}{}", prefix, key)).collect::<Vec<_>>())?;
+        Ok(uploads.into_iter().chain(deletes).collect())
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<Object>> {
+        let listing = output(self.aws()
+                                 .arg("s3api")
+                                 .arg("list-objects-v2")
+                                 .arg("--bucket").arg(&self.bucket)
+                                 .arg("--prefix").arg(prefix)
+                                 .arg("--delimiter").arg("/")
+                                 .arg("--output").arg("text")
+                                 .arg("--query").arg("Contents[].[Key,Size,ETag]"))?;
+        Ok(verify::parse_listing(&listing))
+    }
+
+    fn list_dirs(&self, prefix: &str) -> Result<Vec<String>> {
+        let listing = output(self.aws().arg("s3").arg("ls").arg(self.url(prefix)))?;
+        Ok(listing.lines()
+            .filter_map(|l| l.trim().strip_prefix("PRE "))
+            .map(|d| d.trim_end_matches('/').to_string())
+            .collect())
+    }
+}
+
+/// Publishes the tree in `src` to `prefix` in `storage`, returning the paths
+/// relative to `prefix` that were uploaded or deleted.
+///
+/// Content hashes of what was last published there are kept in storage as
+/// `manifest_key`, so only files whose contents changed get uploaded and only
+/// files that vanished get deleted. Without a readable manifest, the whole
+/// tree is synced instead. The manifest goes through the local file
+/// `scratch`.
+pub fn sync_tree(storage: &dyn Storage, src: &Path, prefix: &str, manifest_key: &str,
+                 scratch: &Path) -> Result<Vec<String>> {
+    let current = hashes::hash_tree(src)?;
+    let previous = if storage.download_file(manifest_key, scratch)? {
+        match hashes::from_json(&fs::read_to_string(scratch)?) {
+            Ok(previous) => Some(previous),
+            Err(e) => {
+                println!("corrupt manifest {}, syncing everything: {}", manifest_key, e);
+                None
+            }
+        }
+    } else {
+        println!("no manifest at {}, syncing everything", manifest_key);
+        None
+    };
+
+    let changed = match previous {
+        Some(previous) => {
+            let diff = hashes::diff(&previous, &current);
+            println!("{} files changed and {} removed in {}",
+                     diff.changed.len(), diff.removed.len(), prefix);
+            storage.upload_files(src, &diff.changed, prefix)?;
+            let removed = diff.removed.iter()
+                .map(|path| format!("{}{}", prefix, path))
+                .collect::<Vec<_>>();
+            storage.delete(&removed)?;
+            diff.changed.into_iter().chain(diff.removed).collect()
+        }
+        None => {
+            let changed = storage.sync_dir(src, prefix)?;
+            println!("{} files changed in {}", changed.len(), prefix);
+            changed
+        }
+    };
+
+    // Only record the new state once the tree itself is fully published.
+    File::create(scratch)?.write_all(hashes::to_json(&current).as_bytes())?;
+    storage.upload_file(scratch, manifest_key)?;
+    Ok(changed)
+}
+
+/// A directory on the local filesystem, with each key a path relative to
+/// it. Serving that directory over HTTP (or pointing `upload-addr` at it
+/// with a `file://` URL) gives a complete release environment without any
+/// cloud account, for example to test releases in CI.
+///
+/// There are no headers to set, so the metadata policy doesn't apply.
+pub struct Local {
+    pub root: PathBuf,
+}
+
+impl Local {
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key.trim_end_matches('/'))
+    }
+}
+
+fn copy(src: &Path, dst: &Path) -> Result<()> {
+    fs::create_dir_all(dst.parent().unwrap())?;
+    fs::copy(src, dst)?;
+    Ok(())
+}
+
+impl Storage for Local {
+    fn upload_files(&self, src: &Path, paths: &[String], prefix: &str) -> Result<()> {
+        let dst = self.path(prefix);
+        for path in paths {
+            copy(&src.join(path), &dst.join(path))?;
+        }
+        Ok(())
+    }
+
+    fn upload_file(&self, src: &Path, key: &str) -> Result<()> {
+        copy(src, &self.path(key))
+    }
+
+    fn download_file(&self, key: &str, dst: &Path) -> Result<bool> {
+        let src = self.path(key);
+        if !src.is_file() {
+            return Ok(false)
+        }
+        fs::copy(src, dst)?;
+        Ok(true)
+    }
+
+    fn delete(&self, keys: &[String]) -> Result<()> {
+        for key in keys {
+            let path = self.path(key);
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn sync_dir(&self, src: &Path, prefix: &str) -> Result<Vec<String>> {
+        let dst = self.path(prefix);
+        let previous = if dst.exists() {
+            hashes::hash_tree(&dst)?
+        } else {
+            hashes::Manifest::new()
+        };
+        let diff = hashes::diff(&previous, &hashes::hash_tree(src)?);
+        for path in diff.changed.iter() {
+            copy(&src.join(path), &dst.join(path))?;
+        }
+        for path in diff.removed.iter() {
+            fs::remove_file(dst.join(path))?;
+        }
+        Ok(diff.changed.into_iter().chain(diff.removed).collect())
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<Object>> {
+        let dir = self.path(prefix);
+        let mut objects = Vec::new();
+        if !dir.is_dir() {
+            return Ok(objects)
+        }
+        for entry in dir.read_dir()? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue
+            }
+            let name = entry.file_name().into_string().expect("non-utf8 file name");
+            objects.push(Object {
+                key: format!("{}{}", prefix, name),
+                size: entry.metadata()?.len(),
+                etag: verify::expected_etag(&entry.path())?,
+            });
+        }
+        Ok(objects)
+    }
+
+    fn list_dirs(&self, prefix: &str) -> Result<Vec<String>> {
+        let dir = self.path(prefix);
+        let mut dirs = Vec::new();
+        if !dir.is_dir() {
+            return Ok(dirs)
+        }
+        for entry in dir.read_dir()? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                dirs.push(entry.file_name().into_string().expect("non-utf8 file name"));
+            }
+        }
+        Ok(dirs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("storage-{}-{}", name, process::id()));
+        drop(fs::remove_dir_all(&dir));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(path: &Path, contents: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    fn keys(storage: &Local, prefix: &str) -> Vec<(String, u64)> {
+        let mut objects = storage.list(prefix).unwrap().into_iter()
+            .map(|o| (o.key, o.size))
+            .collect::<Vec<_>>();
+        objects.sort();
+        objects
+    }
+
+    #[test]
+    fn uploads_and_downloads() {
+        let dir = scratch("upload");
+        let storage = Local { root: dir.join("bucket") };
+        let src = dir.join("src");
+        write(&src.join("a.tar.xz"), "a");
+        write(&src.join("b/c.html"), "c");
+
+        storage.upload_dir(&src, "dist/2020-01-01/").unwrap();
+        storage.upload_file(&src.join("a.tar.xz"), "dist/a.tar.xz").unwrap();
+        assert_eq!(keys(&storage, "dist/"), [("dist/a.tar.xz".to_string(), 1)]);
+        assert_eq!(keys(&storage, "dist/2020-01-01/"),
+                   [("dist/2020-01-01/a.tar.xz".to_string(), 1)]);
+        assert_eq!(storage.list_dirs("dist/").unwrap(), ["2020-01-01"]);
+        assert_eq!(storage.list_dirs("dist/2020-01-01/").unwrap(), ["b"]);
+        assert!(storage.list("missing/").unwrap().is_empty());
+
+        let dst = dir.join("c.html");
+        assert!(storage.download_file("dist/2020-01-01/b/c.html", &dst).unwrap());
+        assert_eq!(fs::read_to_string(&dst).unwrap(), "c");
+        assert!(!storage.download_file("dist/missing", &dst).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn syncs_only_what_changed() {
+        let dir = scratch("sync");
+        let storage = Local { root: dir.join("bucket") };
+        let src = dir.join("docs");
+        write(&src.join("index.html"), "index");
+        write(&src.join("std/index.html"), "std");
+        write(&src.join("old.html"), "old");
+        let mut synced = storage.sync_dir(&src, "doc/nightly/").unwrap();
+        synced.sort();
+        assert_eq!(synced, ["index.html", "old.html", "std/index.html"]);
+
+        write(&src.join("std/index.html"), "std v2");
+        fs::remove_file(src.join("old.html")).unwrap();
+        let mut synced = storage.sync_dir(&src, "doc/nightly/").unwrap();
+        synced.sort();
+        assert_eq!(synced, ["old.html", "std/index.html"]);
+        let bucket = dir.join("bucket/doc/nightly");
+        assert_eq!(fs::read_to_string(bucket.join("std/index.html")).unwrap(), "std v2");
+        assert!(!bucket.join("old.html").exists());
+        assert!(storage.sync_dir(&src, "doc/nightly/").unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn deletes_keys() {
+        let dir = scratch("delete");
+        let storage = Local { root: dir.join("bucket") };
+        write(&dir.join("bucket/dist/a"), "a");
+        write(&dir.join("bucket/dist/b"), "b");
+        // Keys that are already gone are fine.
+        storage.delete(&["dist/a".to_string(), "dist/missing".to_string()]).unwrap();
+        assert_eq!(keys(&storage, "dist/"), [("dist/b".to_string(), 1)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn syncs_trees_by_their_manifest() {
+        let dir = scratch("manifest");
+        let storage = Local { root: dir.join("bucket") };
+        let (src, scratch) = (dir.join("docs"), dir.join("manifest.json"));
+        let bucket = dir.join("bucket/doc/nightly");
+        let sync = || {
+            let mut synced = sync_tree(&storage, &src, "doc/nightly/", "doc-manifests/nightly.json",
+                                       &scratch).unwrap();
+            synced.sort();
+            synced
+        };
+        write(&src.join("index.html"), "index");
+        write(&src.join("std/index.html"), "std");
+        write(&src.join("old.html"), "old");
+        assert_eq!(sync(), ["index.html", "old.html", "std/index.html"]);
+
+        // Only what changed according to the manifest is touched, so a file
+        // that changed in storage behind its back is left alone.
+        write(&bucket.join("index.html"), "tampered");
+        write(&src.join("std/index.html"), "std v2");
+        fs::remove_file(src.join("old.html")).unwrap();
+        assert_eq!(sync(), ["old.html", "std/index.html"]);
+        assert_eq!(fs::read_to_string(bucket.join("std/index.html")).unwrap(), "std v2");
+        assert_eq!(fs::read_to_string(bucket.join("index.html")).unwrap(), "tampered");
+        assert!(!bucket.join("old.html").exists());
+        assert!(sync().is_empty());
+
+        // Without a usable manifest everything is compared.
+        write(&dir.join("bucket/doc-manifests/nightly.json"), "{\"index.html\": ");
+        assert_eq!(sync(), ["index.html"]);
+        assert_eq!(fs::read_to_string(bucket.join("index.html")).unwrap(), "index");
+        fs::remove_file(dir.join("bucket/doc-manifests/nightly.json")).unwrap();
+        write(&bucket.join("index.html"), "tampered");
+        assert_eq!(sync(), ["index.html"]);
+        assert!(sync().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,126 @@
+//! A local HTTP server for tests, answering requests with whatever a handler
+//! says and recording every request it gets.
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Clone)]
+pub enum Reply {
+    /// A response with this status code and body.
+    Status(u32, Vec<u8>),
+    /// Resets the connection without responding.
+    Reset,
+    /// Doesn't respond for this long, then closes the connection.
+    Hang(Duration),
+}
+
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// With the names lowercased.
+    pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|h| h.0 == name).map(|h| &h.1[..])
+    }
+}
+
+pub struct Server {
+    /// Like `http://127.0.0.1:1234`, without a trailing `/`.
+    pub url: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl Server {
+    /// Starts a server on a free port, replying to each request with what
+    /// `handler` returns for it. It keeps running until the test is over.
+    pub fn new<F>(handler: F) -> Server
+        where F: Fn(&Request) -> Reply + Send + Sync + 'static
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        let handler = Arc::new(handler);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let (recorded, handler) = (recorded.clone(), handler.clone());
+                thread::spawn(move || serve(stream.unwrap(), &*handler, &recorded));
+            }
+        });
+        Server { url, requests }
+    }
+
+    /// Starts a server replying to the nth request with `replies[n]`, and
+    /// with the last one once they run out.
+    pub fn sequence(replies: Vec<Reply>) -> Server {
+        let count = AtomicUsize::new(0);
+        Server::new(move |_| {
+            let n = count.fetch_add(1, Ordering::SeqCst);
+            replies.get(n).or(replies.last()).unwrap().clone()
+        })
+    }
+
+    /// Every request received so far.
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+fn serve(mut stream: TcpStream, handler: &dyn Fn(&Request) -> Reply,
+         requests: &Mutex<Vec<Request>>) {
+    // The request is only peeked at, so that if the connection is to be
+    // reset it can be closed with the request still unread, which is what
+    // makes the kernel reset it rather than shut it down cleanly.
+    let mut buf = vec![0; 64 * 1024];
+    let head_len = loop {
+        let n = stream.peek(&mut buf).unwrap();
+        if let Some(i) = buf[..n].windows(4).position(|w| w == b"\r\n\r\n") {
+            break i + 4
+        }
+        if n == buf.len() {
+            panic!("request head too large");
+        }
+        thread::sleep(Duration::from_millis(1));
+    };
+    let head = String::from_utf8_lossy(&buf[..head_len]).into_owned();
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap().split(' ');
+    let request = Request {
+        method: request_line.next().unwrap().to_string(),
+        path: request_line.next().unwrap().to_string(),
+        headers: lines.filter_map(|l| l.split_once(':'))
+            .map(|(name, value)| (name.to_lowercase(), value.trim().to_string()))
+            .collect(),
+    };
+    let reply = handler(&request);
+    let is_head = request.method == "HEAD";
+    let body_len = request.header("content-length").map(|l| l.parse().unwrap()).unwrap_or(0);
+    requests.lock().unwrap().push(request);
+    if let Reply::Reset = reply {
+        return
+    }
+
+    let mut consumed = vec![0; head_len + body_len];
+    stream.read_exact(&mut consumed).unwrap();
+    match reply {
+        Reply::Status(code, body) => {
+            let head = format!("HTTP/1.1 {} Whatever\r\nContent-Length: {}\r\n\
+                                Connection: close\r\n\r\n", code, body.len());
+            // The client may have given up on us already.
+            drop(stream.write_all(head.as_bytes()));
+            if !is_head {
+                drop(stream.write_all(&body));
+            }
+        }
+        Reply::Hang(duration) => thread::sleep(duration),
+        Reply::Reset => unreachable!(),
+    }
+}
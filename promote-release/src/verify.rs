@@ -0,0 +1,440 @@
+//! Checks that what ended up in the bucket is what we meant to publish.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use md5::{Digest, Md5};
+
+use gpg::Verifier;
+use hashes;
+use http::{self, Client};
+
+/// Size of the parts `aws s3 cp` splits uploads into, and the size above
+/// which it starts doing so. These are the CLI's defaults.
+const MULTIPART_CHUNK: u64 = 8 * 1024 * 1024;
+
+/// Computes the ETag S3 reports for the file at `path` once it's been
+/// uploaded by `aws s3 cp`.
+///
+/// That's the MD5 of the contents for files uploaded in one go. Multipart
+/// uploads instead get the MD5 of the concatenated MD5s of each part, with
+/// the number of parts appended.
+pub fn expected_etag(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    if len < MULTIPART_CHUNK {
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        return Ok(hex::encode(Md5::digest(&contents)))
+    }
+
+    let mut part_hashes = Vec::new();
+    let mut parts = 0;
+    let mut buf = vec![0; MULTIPART_CHUNK as usize];
+    loop {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match file.read(&mut buf[filled..])? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        if filled == 0 {
+            break
+        }
+        part_hashes.extend_from_slice(&Md5::digest(&buf[..filled]));
+        parts += 1;
+    }
+    Ok(format!("{}-{}", hex::encode(Md5::digest(&part_hashes)), parts))
+}
+
+/// An object as listed by `list-objects-v2`.
+pub struct Object {
+    pub key: String,
+    pub size: u64,
+    pub etag: String,
+}
+
+/// Parses the output of `aws s3api list-objects-v2 --output text --query
+/// 'Contents[].[Key,Size,ETag]'`, which is one tab-separated object per line.
+pub fn parse_listing(listing: &str) -> Vec<Object> {
+    listing.lines().filter_map(|line| {
+        let mut parts = line.split('\t');
+        let key = parts.next()?;
+        let size = parts.next()?.parse().ok()?;
+        let etag = parts.next()?.trim_matches('"');
+        Some(Object {
+            key: key.to_string(),
+            size,
+            etag: etag.to_string(),
+        })
+    }).collect()
+}
+
+/// A file that's meant to have been published.
+pub struct Expected {
+    pub name: String,
+    pub size: u64,
+    /// As listed in the release's `SHA256SUMS`, if it's covered by that.
+    pub sha256: Option<String>,
+}
+
+/// Checks that each of the `expected` files is among the `objects` listed
+/// under `prefix` with the same size, describing every one that isn't.
+///
+/// ETags aren't compared, as they're only a hash of the contents for some
+/// uploads. The contents are checked with `compare_sums` instead.
+pub fn compare(prefix: &str, expected: &[Expected], objects: Vec<Object>) -> Vec<String> {
+    let objects = objects.into_iter()
+        .map(|o| (o.key.clone(), o))
+        .collect::<BTreeMap<_, _>>();
+    println!("verifying {} files against {} objects in {}",
+             expected.len(), objects.len(), prefix);
+    let mut problems = Vec::new();
+    for e in expected {
+        let key = format!("{}{}", prefix, e.name);
+        match objects.get(&key) {
+            None => problems.push(format!("{} is missing", key)),
+            Some(o) if o.size != e.size => {
+                problems.push(format!("{} is {} bytes, expected {}", key, o.size, e.size));
+            }
+            Some(_) => {}
+        }
+    }
+    problems
+}
+
+/// Checks the hashes in `sums`, the contents of the `SHA256SUMS` published
+/// as `key`, against those of the `expected` files, describing every file it
+/// has a different hash for or none at all.
+pub fn compare_sums(key: &str, sums: &str, expected: &[Expected]) -> Vec<String> {
+    let published = hashes::parse_sums(sums);
+    let mut problems = Vec::new();
+    for e in expected {
+        let sha256 = match e.sha256 {
+            Some(ref sha256) => sha256,
+            None => continue,
+        };
+        match published.get(&e.name) {
+            None => problems.push(format!("{} doesn't list {}", key, e.name)),
+            Some(hash) if hash != sha256 => {
+                problems.push(format!("{} lists {} with hash {}, expected {}",
+                                      key, e.name, hash, sha256));
+            }
+            Some(_) => {}
+        }
+    }
+    problems
+}
+
+/// Collects every artifact URL referenced by a channel manifest, which is
+/// each target's `url` and `xz_url` of every package, and the `url` of each
+/// of the `[artifacts]`, like the source tarball.
+pub fn manifest_urls(manifest: &toml::Value) -> Vec<String> {
+    let mut urls = Vec::new();
+    let mut add = |target: &toml::Value, keys: &[&str]| {
+        for key in keys {
+            if let Some(url) = target.get(*key).and_then(|u| u.as_str()) {
+                urls.push(url.to_string());
+            }
+        }
+    };
+    let targets = |section: &str| {
+        manifest.get(section)
+            .and_then(|s| s.as_table())
+            .into_iter()
+            .flat_map(|s| s.values())
+            .filter_map(|item| item.get("target").and_then(|t| t.as_table()))
+            .flat_map(|targets| targets.values())
+    };
+    for target in targets("pkg") {
+        add(target, &["url", "xz_url"]);
+    }
+    // Each target of an artifact is an array, with an entry per format.
+    for target in targets("artifacts") {
+        for entry in target.as_array().into_iter().flatten() {
+            add(entry, &["url"]);
+        }
+    }
+    urls.sort();
+    urls.dedup();
+    urls
+}
+
+/// Fetches the live manifest at `url` along with its `.sha256` and `.asc`,
+/// and checks that they agree and that it's signed by the release key.
+/// Returns `None` if there's no manifest published there yet.
+///
+/// The CDN caches the three files separately, so right after a release they
+/// can come from different ones. A mismatch is therefore only believed once
+/// all three have been fetched again `attempts` times, `delay` apart. The
+/// files are checked in `dir`.
+pub fn live_manifest(http: &mut Client,
+                     url: &str,
+                     verifier: &Verifier,
+                     dir: &Path,
+                     attempts: u32,
+                     delay: Duration) -> http::Result<Option<Vec<u8>>> {
+    let mut attempt = 1;
+    loop {
+        let problem = match check_live_manifest(http, url, verifier, dir)? {
+            Ok(manifest) => return Ok(manifest),
+            Err(problem) => problem,
+        };
+        if attempt >= attempts {
+            return Err(problem.into())
+        }
+        println!("{}, fetching it again in {:?}", problem, delay);
+        thread::sleep(delay);
+        attempt += 1;
+    }
+}
+
+/// One attempt of `live_manifest`, with the inner error saying how the files
+/// disagree.
+fn check_live_manifest(http: &mut Client,
+                       url: &str,
+                       verifier: &Verifier,
+                       dir: &Path) -> http::Result<Result<Option<Vec<u8>>, String>> {
+    let response = http.get(url)?;
+    let manifest = match response.code {
+        200 => response.body,
+        404 => return Ok(Ok(None)),
+        code => return Err(format!("failed to download {}: {}", url, code).into()),
+    };
+    let sha256 = http.get_ok(&format!("{}.sha256", url))?;
+    let signature = http.get_ok(&format!("{}.asc", url))?;
+
+    let expected = String::from_utf8_lossy(&sha256);
+    let expected = expected.split_whitespace().next().unwrap_or("");
+    let actual = hashes::sha256(&manifest);
+    if actual != expected {
+        return Ok(Err(format!("live manifest {} has hash {} but its .sha256 says {}",
+                              url, actual, expected)))
+    }
+
+    fs::create_dir_all(dir)?;
+    let manifest_path = dir.join("manifest.toml");
+    let signature_path = dir.join("manifest.toml.asc");
+    fs::write(&manifest_path, &manifest)?;
+    fs::write(&signature_path, &signature)?;
+    if !verifier.verify(&manifest_path, &signature_path)? {
+        return Ok(Err(format!("live manifest {} isn't signed by the release key", url)))
+    }
+    Ok(Ok(Some(manifest)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+    use gpg::tests::{keygen, sign};
+    use test_server::{Reply, Server};
+
+    fn etag_of(name: &str, contents: &[u8]) -> String {
+        let path = env::temp_dir().join(format!("verify-{}-{}", name, process::id()));
+        fs::write(&path, contents).unwrap();
+        let etag = expected_etag(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        etag
+    }
+
+    #[test]
+    fn single_part_etags_are_md5s() {
+        assert_eq!(etag_of("empty", b""), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(etag_of("hello", b"hello"), "5d41402abc4b2a76b9719d911017c592");
+    }
+
+    #[test]
+    fn multipart_etags_hash_each_part() {
+        let chunk = MULTIPART_CHUNK as usize;
+        let contents = (0..chunk * 2 + 1).map(|i| i as u8).collect::<Vec<_>>();
+        let mut parts = Vec::new();
+        for part in contents.chunks(chunk) {
+            parts.extend_from_slice(&Md5::digest(part));
+        }
+        assert_eq!(etag_of("multipart", &contents),
+                   format!("{}-3", hex::encode(Md5::digest(&parts))));
+
+        let exact = &contents[..chunk];
+        assert_eq!(etag_of("exact", exact),
+                   format!("{}-1", hex::encode(Md5::digest(&Md5::digest(exact)))));
+    }
+
+    #[test]
+    fn parses_listings() {
+        let listing = "dist/a b.tar.xz\t10\t\"0123\"\n\
+                       dist/c.tar.xz\t2000\t\"4567-2\"\n\
+                       None\n\
+                       dist/bad\tsize\t\"89ab\"\n";
+        let objects = parse_listing(listing);
+        let found = objects.iter()
+            .map(|o| (&o.key[..], o.size, &o.etag[..]))
+            .collect::<Vec<_>>();
+        assert_eq!(found, [("dist/a b.tar.xz", 10, "0123"), ("dist/c.tar.xz", 2000, "4567-2")]);
+    }
+
+    fn expected(name: &str, size: u64, sha256: Option<&str>) -> Expected {
+        Expected {
+            name: name.to_string(),
+            size,
+            sha256: sha256.map(|s| s.to_string()),
+        }
+    }
+
+    #[test]
+    fn compares_listings() {
+        // S3 gives objects encrypted with SSE-KMS ETags that aren't MD5s.
+        let objects = parse_listing("dist/a\t1\t\"00\"\ndist/b\t2\t\"11\"\n");
+        let expected = [
+            expected("a", 1, Some("aa")),
+            expected("b", 3, Some("bb")),
+            expected("c", 4, None),
+        ];
+        assert_eq!(compare("dist/", &expected, objects), [
+            "dist/b is 2 bytes, expected 3",
+            "dist/c is missing",
+        ]);
+    }
+
+    #[test]
+    fn compares_sums() {
+        let expected = [
+            expected("a", 1, Some("aa")),
+            expected("a.sha256", 1, None),
+            expected("b", 1, Some("bb")),
+            expected("c", 1, Some("cc")),
+        ];
+        assert_eq!(compare_sums("dist/SHA256SUMS", "aa  a\nbc  b\n", &expected), [
+            "dist/SHA256SUMS lists b with hash bc, expected bb",
+            "dist/SHA256SUMS doesn't list c",
+        ]);
+    }
+
+    #[test]
+    fn lists_manifest_urls() {
+        let manifest = "[pkg.rust.target.x86_64-unknown-linux-gnu]\n\
+                        url = \"https://example.com/b.tar.gz\"\n\
+                        xz_url = \"https://example.com/b.tar.xz\"\n\
+                        [pkg.rust-std.target.x86_64-unknown-linux-gnu]\n\
+                        url = \"https://example.com/a.tar.gz\"\n\
+                        [pkg.rust-std.target.wasm32-unknown-unknown]\n\
+                        available = false\n\
+                        url = \"https://example.com/a.tar.gz\"\n\
+                        [[artifacts.source-code.target.\"*\"]]\n\
+                        url = \"https://example.com/src.tar.gz\"\n\
+                        hash-sha256 = \"00\"\n\
+                        [[artifacts.source-code.target.\"*\"]]\n\
+                        url = \"https://example.com/src.tar.xz\"\n\
+                        hash-sha256 = \"11\"\n";
+        assert_eq!(manifest_urls(&manifest.parse().unwrap()), [
+            "https://example.com/a.tar.gz",
+            "https://example.com/b.tar.gz",
+            "https://example.com/b.tar.xz",
+            "https://example.com/src.tar.gz",
+            "https://example.com/src.tar.xz",
+        ]);
+    }
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("verify-{}-{}", name, process::id()));
+        drop(fs::remove_dir_all(&dir));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    const MANIFEST: &[u8] = b"manifest-version = \"2\"\n";
+    const OLD_MANIFEST: &[u8] = b"manifest-version = \"1\"\n";
+
+    /// Serves `/channel.toml` with `sha256` and `signature` as its sidecars.
+    /// The manifest itself is `stale` for that many requests before it's
+    /// `MANIFEST`.
+    fn serve_manifest(sha256: String, signature: Vec<u8>, stale: usize) -> (Server, String) {
+        let served = Arc::new(AtomicUsize::new(0));
+        let server = Server::new(move |request| {
+            match &request.path[..] {
+                "/channel.toml" => {
+                    if served.fetch_add(1, Ordering::SeqCst) < stale {
+                        Reply::Status(200, OLD_MANIFEST.to_vec())
+                    } else {
+                        Reply::Status(200, MANIFEST.to_vec())
+                    }
+                }
+                "/channel.toml.sha256" => Reply::Status(200, sha256.clone().into_bytes()),
+                "/channel.toml.asc" => Reply::Status(200, signature.clone()),
+                _ => Reply::Status(404, Vec::new()),
+            }
+        });
+        let url = format!("{}/channel.toml", server.url);
+        (server, url)
+    }
+
+    fn fetch(url: &str, verifier: &Verifier, dir: &Path) -> http::Result<Option<Vec<u8>>> {
+        let mut client = Client::new(&toml::Value::Table(Default::default()));
+        live_manifest(&mut client, url, verifier, &dir.join("live"), 3,
+                      Duration::from_millis(10))
+    }
+
+    #[test]
+    fn accepts_a_signed_manifest() {
+        let dir = scratch("signed");
+        let (home, verifier) = keygen(&dir);
+        let sha256 = format!("{}  channel.toml\n", hashes::sha256(MANIFEST));
+        let (server, url) = serve_manifest(sha256, sign(&home, MANIFEST, &dir), 0);
+        assert_eq!(fetch(&url, &verifier, &dir).unwrap().unwrap(), MANIFEST);
+        assert_eq!(server.requests().len(), 3);
+
+        let missing = format!("{}/missing.toml", server.url);
+        assert!(fetch(&missing, &verifier, &dir).unwrap().is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refetches_a_set_from_different_generations() {
+        let dir = scratch("generations");
+        let (home, verifier) = keygen(&dir);
+        let sha256 = format!("{}  channel.toml\n", hashes::sha256(MANIFEST));
+        let (server, url) = serve_manifest(sha256, sign(&home, MANIFEST, &dir), 2);
+        assert_eq!(fetch(&url, &verifier, &dir).unwrap().unwrap(), MANIFEST);
+        assert_eq!(server.requests().len(), 9);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_a_tampered_manifest() {
+        let dir = scratch("tampered");
+        let (home, verifier) = keygen(&dir);
+        let sha256 = format!("{}  channel.toml\n", hashes::sha256(OLD_MANIFEST));
+        let (server, url) = serve_manifest(sha256, sign(&home, OLD_MANIFEST, &dir), 0);
+        let err = fetch(&url, &verifier, &dir).unwrap_err();
+        assert_eq!(err.to_string(), format!("live manifest {} has hash {} but its .sha256 says {}",
+                                            url, hashes::sha256(MANIFEST),
+                                            hashes::sha256(OLD_MANIFEST)));
+        assert_eq!(server.requests().len(), 9);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_a_bad_signature() {
+        let dir = scratch("signature");
+        let (_, verifier) = keygen(&dir);
+        let (other, _) = keygen(&dir.join("other"));
+        let sha256 = format!("{}  channel.toml\n", hashes::sha256(MANIFEST));
+        let (_server, url) = serve_manifest(sha256, sign(&other, MANIFEST, &dir), 0);
+        let err = fetch(&url, &verifier, &dir).unwrap_err();
+        assert_eq!(err.to_string(),
+                   format!("live manifest {} isn't signed by the release key", url));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
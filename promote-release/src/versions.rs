@@ -0,0 +1,155 @@
+//! The index of stable versions whose docs are published under
+//! `/doc/$version/`.
+
+use std::collections::BTreeMap;
+
+/// A published version along with the date it was released on, if known.
+pub struct Version {
+    pub version: String,
+    pub date: Option<String>,
+}
+
+/// Parses `1.40.0` into its numeric components, or returns `None` if `s`
+/// isn't a `major.minor.patch` version.
+pub fn parse(s: &str) -> Option<Vec<u32>> {
+    let parts = s.split('.').map(|p| p.parse().ok()).collect::<Option<Vec<u32>>>()?;
+    if parts.len() == 3 {
+        Some(parts)
+    } else {
+        None
+    }
+}
+
+/// Builds the list of `versions`, newest first, taking their release dates
+/// from `dates`.
+pub fn collect(versions: &[String], dates: &BTreeMap<String, String>) -> Vec<Version> {
+    let mut versions = versions.iter()
+        .filter_map(|v| parse(v).map(|parsed| (parsed, v)))
+        .collect::<Vec<_>>();
+    versions.sort();
+    versions.dedup();
+    versions.into_iter().rev().map(|(_, v)| {
+        Version {
+            version: v.clone(),
+            date: dates.get(v).cloned(),
+        }
+    }).collect()
+}
+
+/// Renders `versions` as `versions.json`, an array of
+/// `{"version", "date", "path"}` objects.
+pub fn to_json(versions: &[Version]) -> String {
+    let versions = versions.iter().map(|v| {
+        json!({
+            "version": v.version,
+            "date": v.date,
+            "path": format!("/{}/", v.version),
+        })
+    }).collect::<Vec<_>>();
+    serde_json::to_string_pretty(&versions).unwrap()
+}
+
+/// Renders `versions` as an HTML page linking to each version's docs.
+pub fn to_html(versions: &[Version]) -> String {
+    let mut items = String::new();
+    for v in versions {
+        let date = v.date.as_ref().map(|d| format!(" ({})", d)).unwrap_or_default();
+        items.push_str(&format!("<li><a href=\"{0}/index.html\">{0}</a>{1}</li>\n",
+                                v.version, date));
+    }
+    format!("<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<title>Rust documentation versions</title>
+</head>
+<body>
+<h1>Rust documentation versions</h1>
+<ul>
+{}</ul>
+</body>
+</html>
+", items)
+}
+
+/// Maps each `major.minor` in `versions` to its newest patch release, for
+/// which a redirect stub is published at `/$major.$minor/`.
+pub fn latest_patches(versions: &[Version]) -> BTreeMap<String, String> {
+    let mut latest = BTreeMap::new();
+    // `versions` is newest first, so the first one seen wins.
+    for v in versions {
+        let minor = v.version.rsplit_once('.').unwrap().0.to_string();
+        latest.entry(minor).or_insert_with(|| v.version.clone());
+    }
+    latest
+}
+
+/// A page redirecting to the docs of `version`, placed one directory below
+/// the docs root.
+pub fn redirect_stub(version: &str) -> String {
+    format!("<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<meta http-equiv=\"refresh\" content=\"0; url=../{0}/index.html\">
+<link rel=\"canonical\" href=\"../{0}/index.html\">
+<title>Redirecting to Rust {0} documentation</title>
+</head>
+<body>
+<p><a href=\"../{0}/index.html\">Rust {0} documentation</a></p>
+</body>
+</html>
+", version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(versions: &[&str]) -> Vec<String> {
+        versions.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_versions() {
+        assert_eq!(parse("1.40.0"), Some(vec![1, 40, 0]));
+        assert_eq!(parse("1.40"), None);
+        assert_eq!(parse("1.40.0.1"), None);
+        assert_eq!(parse("1.41.0-beta"), None);
+        assert_eq!(parse("nightly"), None);
+        assert_eq!(parse(""), None);
+    }
+
+    #[test]
+    fn orders_versions_numerically() {
+        let mut dates = BTreeMap::new();
+        dates.insert("1.10.0".to_string(), "2016-07-07".to_string());
+        let versions = collect(&strings(&["1.9.0", "1.10.0", "beta", "1.2.0", "1.10.0", "1.9.1"]),
+                               &dates);
+        let found = versions.iter()
+            .map(|v| (&v.version[..], v.date.as_deref()))
+            .collect::<Vec<_>>();
+        assert_eq!(found, [
+            ("1.10.0", Some("2016-07-07")),
+            ("1.9.1", None),
+            ("1.9.0", None),
+            ("1.2.0", None),
+        ]);
+    }
+
+    #[test]
+    fn finds_latest_patches() {
+        let versions = collect(&strings(&["1.9.0", "1.10.0", "1.9.1"]), &BTreeMap::new());
+        let latest = latest_patches(&versions);
+        assert_eq!(latest.len(), 2);
+        assert_eq!(latest["1.9"], "1.9.1");
+        assert_eq!(latest["1.10"], "1.10.0");
+    }
+
+    #[test]
+    fn renders_json() {
+        let versions = collect(&strings(&["1.0.0"]), &BTreeMap::new());
+        let json = serde_json::from_str::<serde_json::Value>(&to_json(&versions)).unwrap();
+        assert_eq!(json, json!([{"version": "1.0.0", "date": null, "path": "/1.0.0/"}]));
+    }
+}